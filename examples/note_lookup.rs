@@ -0,0 +1,56 @@
+// Compares finding the notes for each playback tick and the notes on screen by going through every note
+// against the time sorted lookups in Layer, run with `cargo run --release --example note_lookup`
+use std::{hint::black_box, time::{Duration, Instant}};
+
+#[allow(dead_code)]
+#[path = "../src/project.rs"]
+mod project;
+
+use project::{Layer, Note, Project};
+
+// ticks on screen at the default zoom
+const VIEWPORT: u32 = 40;
+
+fn song(notes: u32) -> Project {
+	let mut project = Project::new();
+	project.layers.clear();
+	for index in 0..8 {
+		project.layers.push(Layer::new(format!("Layer {}", index + 1), 0));
+	}
+	// a few notes per tick, spread over the layers
+	for index in 0..notes {
+		let layer = (index % 8) as usize;
+		project.add_note(layer, Note::new(index / 3, 54 + (index * 7 % 25) as u8));
+	}
+	project
+}
+
+fn time(f: impl FnOnce() -> usize) -> (Duration, usize) {
+	let start = Instant::now();
+	let count = black_box(f());
+	(start.elapsed(), count)
+}
+
+fn main() {
+	println!("{:>8} {:>14} {:>14} {:>14} {:>14}", "notes", "playback scan", "playback", "viewport scan", "viewport");
+	for notes in [1_000, 10_000, 50_000, 200_000] {
+		let project = song(notes);
+		let length = notes / 3 + 1;
+
+		// every tick of the song once, like playing it from the start
+		let (scan, scan_count) = time(|| (0..length).map(|tick| project.layers.iter().map(|layer| layer.notes.iter().filter(|note| note.time == tick).count()).sum::<usize>()).sum());
+		let (indexed, indexed_count) = time(|| (0..length).map(|tick| project.layers.iter().map(|layer| layer.notes_at(tick).len()).sum::<usize>()).sum());
+		assert_eq!(scan_count, indexed_count);
+
+		// scrolling through the whole song a screen at a time
+		let screens = (0..length).step_by(VIEWPORT as usize);
+		let (view_scan, view_scan_count) = time(|| screens.clone().map(|start| project.layers.iter().map(|layer| layer.notes.iter().filter(|note| (start..start + VIEWPORT).contains(&note.time)).count()).sum::<usize>()).sum());
+		let (view_indexed, view_indexed_count) = time(|| screens.clone().map(|start| project.layers.iter().map(|layer| layer.notes_between(start..start + VIEWPORT).len()).sum::<usize>()).sum());
+		assert_eq!(view_scan_count, view_indexed_count);
+
+		// per tick and per screen, what one frame pays
+		let per = |duration: Duration, count: u32| format!("{:.2?}", duration / count.max(1));
+		let screen_count = length.div_ceil(VIEWPORT);
+		println!("{notes:>8} {:>14} {:>14} {:>14} {:>14}", per(scan, length), per(indexed, length), per(view_scan, screen_count), per(view_indexed, screen_count));
+	}
+}
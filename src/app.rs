@@ -1,12 +1,106 @@
-use std::{fs::File, io::{BufReader, BufWriter}, path::PathBuf, u32};
+use std::{collections::HashSet, fs::File, io::{self, BufReader, BufWriter, Write}, path::{Path, PathBuf}, u32};
 
 use eframe::egui::{self, Color32, Key, Pos2, Rect, Stroke, pos2, vec2};
-use lewton::inside_ogg::OggStreamReader;
-use rodio::{OutputStream, Source, buffer::SamplesBuffer};
+use rodio::{OutputStream, Source, buffer::SamplesBuffer, source::ChannelVolume};
 
-use crate::project::{self, Layer, Project};
+use crate::{clipboard::Clipboard, datapack::{self, DatapackOptions}, history::History, midi::{self, MidiExportOptions, MidiImport}, nbs, project::{self, CustomInstrument, FIRST_CUSTOM_INSTRUMENT, Layer, MAX_CUSTOM_INSTRUMENTS, MIN_TPS, Note, NoteId, Project}, render::{self, BitDepth, WavOptions}, schematic, sequencer::{Player, Song}, sounds};
 
-const SOUND_FILE_NAMES: [&str; 16] = ["harp", "dbass", "bdrum", "sdrum", "click", "guitar", "flute", "bell", "icechime", "xylobone", "iron_xylophone", "cow_bell", "didgeridoo", "bit", "banjo", "pling"];
+pub const SOUND_FILE_NAMES: [&str; 16] = ["harp", "dbass", "bdrum", "sdrum", "click", "guitar", "flute", "bell", "icechime", "xylobone", "iron_xylophone", "cow_bell", "didgeridoo", "bit", "banjo", "pling"];
+// what minecraft calls them, for the note block instrument block state and the block.note_block.* sounds
+pub const MINECRAFT_INSTRUMENT_NAMES: [&str; 16] = ["harp", "bass", "basedrum", "snare", "hat", "guitar", "flute", "bell", "chime", "xylophone", "iron_xylophone", "cow_bell", "didgeridoo", "bit", "banjo", "pling"];
+
+// note tints for View > Color by instrument, in SOUND_FILE_NAMES order
+const INSTRUMENT_COLORS: [[u8; 3]; 16] = [
+	[90, 160, 255], [150, 90, 60], [220, 80, 80], [240, 220, 120], [200, 200, 200], [230, 150, 80], [170, 120, 220], [255, 215, 0],
+	[160, 230, 255], [245, 240, 220], [180, 190, 200], [200, 140, 90], [130, 110, 60], [100, 240, 120], [240, 230, 130], [255, 140, 220],
+];
+
+// height of the note property lane under the piano roll
+const LANE_HEIGHT: f32 = 60.0;
+// height of the ruler above the piano roll
+const RULER_HEIGHT: f32 = 32.0;
+
+// Layer panel changes, done after the panel has been drawn
+enum LayerAction {
+	Duplicate(usize),
+	// a new layer at this index
+	Insert(usize),
+	Delete(usize),
+	// (from, to), to is the index before the layer is taken out
+	Move(usize, usize),
+}
+
+// What the lane under the piano roll edits
+#[derive(Clone, Copy, PartialEq)]
+enum Lane {
+	Volume,
+	Pan,
+	Pitch,
+}
+
+impl Lane {
+	const ALL: [Lane; 3] = [Lane::Volume, Lane::Pan, Lane::Pitch];
+
+	fn name(self) -> &'static str {
+		match self {
+			Lane::Volume => "Volume",
+			Lane::Pan => "Pan",
+			Lane::Pitch => "Pitch",
+		}
+	}
+
+	// 0.0 is the bottom of the lane and 1.0 the top
+	fn get(self, note: &project::Note) -> f32 {
+		match self {
+			Lane::Volume => note.gain(),
+			Lane::Pan => (note.pan as f32 + 100.0) / 200.0,
+			// only +-100 cents fit in the lane, bigger offsets still play but stick to the edge
+			Lane::Pitch => (note.cents.clamp(-100, 100) as f32 + 100.0) / 200.0,
+		}
+	}
+
+	// where the bars start from
+	fn base(self) -> f32 {
+		match self {
+			Lane::Volume => 0.0,
+			Lane::Pan | Lane::Pitch => 0.5,
+		}
+	}
+
+	fn set(self, note: &mut project::Note, value: f32) {
+		match self {
+			Lane::Volume => note.volume = (value * 100.0).round() as u8,
+			Lane::Pan => note.pan = (value * 200.0 - 100.0).round() as i8,
+			Lane::Pitch => note.cents = (value * 200.0 - 100.0).round() as i16,
+		}
+	}
+
+	fn format(self, value: f32) -> String {
+		match self {
+			Lane::Volume => format!("{}%", (value * 100.0).round()),
+			Lane::Pan => match (value * 200.0 - 100.0).round() as i32 {
+				0 => "Center".to_string(),
+				pan if pan < 0 => format!("L {}", -pan),
+				pan => format!("R {pan}"),
+			},
+			Lane::Pitch => format!("{:+} cents", (value * 200.0 - 100.0).round()),
+		}
+	}
+}
+
+// File helpers that turn every failure into a message for the error dialog
+fn read_file<T>(path: &Path, read: impl FnOnce(&mut BufReader<File>) -> io::Result<T>) -> Result<T, String> {
+	File::open(path).and_then(|file| read(&mut BufReader::new(file))).map_err(|error| format!("Couldn't open {}\n\n{error}", path.display()))
+}
+
+fn write_file<T>(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<T>) -> Result<T, String> {
+	File::create(path).and_then(|file| {
+		let mut writer = BufWriter::new(file);
+		let value = write(&mut writer)?;
+		writer.flush()?;
+		Ok(value)
+	}).map_err(|error| format!("Couldn't save {}\n\n{error}", path.display()))
+}
 
 pub struct App {
 	project: Project,
@@ -14,52 +108,89 @@ pub struct App {
 
 	current_layer: usize,
 
+	// a copy of the player's position for drawing, f32::MIN when stopped
 	playback_time: f32,
-	playing: bool,
+	// where the last play started from
+	play_start: f32,
+	return_to_start: bool,
+	loop_region: Option<(u32, u32)>,
+	looping: bool,
+	loop_drag_start: u32,
 
 	scroll: f32,
 	vscroll: f32,
 
 	stream: OutputStream,
 	noteblock_sounds: Vec<SamplesBuffer>,
+	player: Player,
+	// the player needs a new Song before it plays again
+	song_changed: bool,
 	
-	tps: f32,
-	last_played_note: u8, last_playback_time_tick: u32,
+	last_played_note: u8,
 	noteblock_texture: egui::TextureHandle,
 
 	selection_start: Pos2,
 	selection_end: Pos2,
-	selected_notes: Vec<usize>,
+	selected_notes: HashSet<NoteId>,
+
+	history: History,
+	editing: Option<egui::Id>,
 	
 	unsaved_changes: bool,
 	show_unsaved_changes_confirmation_dialogue_modal_because_exit: bool,
 	show_unsaved_changes_confirmation_dialogue_modal_because_new: bool,
+	message: Option<String>,
+	error: Option<String>,
+	wav_options: WavOptions,
+	show_wav_export: bool,
+	midi_import: Option<MidiImport>,
+	midi_export_options: MidiExportOptions,
+	show_midi_export: bool,
+	datapack_options: DatapackOptions,
+	show_datapack_export: bool,
+	show_custom_instruments: bool,
+	show_tempo: bool,
+	show_markers: bool,
+	lane: Lane,
+	color_by_instrument: bool,
+	only_current_layer: bool,
+	// the last copied notes, for Edit > Paste
+	clipboard: Option<Clipboard>,
+	paste_at_mouse: bool,
+	paste_into_original_layers: bool,
 }
 
 impl App {
-	pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
+	pub fn new(cc: &eframe::CreationContext<'_>, noteblock_sounds: Vec<SamplesBuffer>) -> Self {
 		cc.egui_ctx.set_visuals(egui::Visuals::dark());
 		let mut stream = rodio::OutputStreamBuilder::open_default_stream().expect("Failed to open stream!");
 		stream.log_on_drop(false);
-
-		let mut noteblock_sounds: Vec<SamplesBuffer> = Vec::new();
-
-		for sound in SOUND_FILE_NAMES {
-			noteblock_sounds.push(Self::load_ogg(&format!("sounds/{sound}.ogg")));
-		}
+		let player = Player::new(&stream);
+		player.set_sounds(&noteblock_sounds);
 
 		Self {
 			project: Project::new(), project_path: None,
 			current_layer: 0,
-			playback_time: f32::MIN, playing: false,
+			playback_time: f32::MIN, play_start: f32::MIN, return_to_start: false,
+			loop_region: None, looping: false, loop_drag_start: 0,
 			scroll: 0.0, vscroll: 54.0,
-			tps: 10.0,
-			last_played_note: 255, last_playback_time_tick: 0,
+			last_played_note: 255,
 			noteblock_texture: cc.egui_ctx.load_texture("noteblock", egui::ColorImage::from_rgba_unmultiplied([16, 16], include_bytes!("noteblock.bin")), egui::TextureOptions::default()),
 			unsaved_changes: false,
-			stream, noteblock_sounds,
-			selection_start: pos2(0.0, 0.0), selection_end: pos2(0.0, 0.0), selected_notes: Vec::new(),
+			stream, noteblock_sounds, player, song_changed: true,
+			selection_start: pos2(0.0, 0.0), selection_end: pos2(0.0, 0.0), selected_notes: HashSet::new(),
+			history: History::new(), editing: None,
 			show_unsaved_changes_confirmation_dialogue_modal_because_exit: false, show_unsaved_changes_confirmation_dialogue_modal_because_new: false,
+			message: None, error: None,
+			wav_options: WavOptions::new(), show_wav_export: false,
+			midi_import: None, midi_export_options: MidiExportOptions::new(), show_midi_export: false,
+			datapack_options: DatapackOptions::new(), show_datapack_export: false,
+			show_custom_instruments: false,
+			show_tempo: false, show_markers: false,
+			lane: Lane::Volume,
+			color_by_instrument: false,
+			only_current_layer: false,
+			clipboard: None, paste_at_mouse: true, paste_into_original_layers: false,
 		}
 	}
 
@@ -68,26 +199,318 @@ impl App {
 		format!("{}{}", NOTE_NAMES[(note % 12) as usize], (note / 12) as i32 - 1)
 	}
 
-	fn load_ogg(path: &str) -> SamplesBuffer {
-		let mut reader = OggStreamReader::new(File::open(path).expect("Audio file not found!")).expect("Failed to read audio file!");
-		let channels: u16 = 1;
-		let sample_rate = reader.ident_hdr.audio_sample_rate;
+	// pitch is in semitones, like Note::pitch
+	fn play_note(&self, pitch: f32, instrument: u8, volume: f32, pan: f32) {
+		let sink = rodio::Sink::connect_new(self.stream.mixer());
+
+		let Some(sound) = self.noteblock_sounds.get(instrument as usize) else { return };
+		let sound = sound.clone().speed(render::note_speed(pitch, self.project.base_note(instrument))).amplify(volume);
+		let (left, right) = render::pan_gains(pan);
+		sink.append(ChannelVolume::new(sound, vec![left, right]));
+
+		sink.detach();
+	}
 
-		let mut sampels: Vec<f32> = Vec::new();
-		while let Some(packet) = reader.read_dec_packet_generic::<Vec<Vec<f32>>>().unwrap() {
-			sampels.extend(&packet[0]);
+	fn toggle_playback(&mut self) {
+		if self.player.is_playing() {
+			self.player.pause();
+			return;
 		}
+		if self.playback_time < 0.0 {
+			self.playback_time = 0.0;
+			self.player.seek(0.0);
+		}
+		self.play_start = self.playback_time;
+		self.update_song();
+		self.player.play();
+	}
 
-		SamplesBuffer::new(channels, sample_rate, sampels)
+	// From the first selected note, or the playback line if nothing is selected
+	fn play_from_selection(&mut self) {
+		let start = self.project.layers.iter().flat_map(|layer| &layer.notes).filter(|note| self.selected_notes.contains(&note.id)).map(|note| note.time).min();
+		if let Some(start) = start {
+			self.playback_time = start as f32;
+			self.player.seek(start as f64);
+		}
+		if self.player.is_playing() {
+			self.play_start = self.playback_time;
+		} else {
+			self.toggle_playback();
+		}
 	}
 
-	fn play_note(&self, note: u8, instrument: u8) {
-		let sink = rodio::Sink::connect_new(self.stream.mixer());
+	fn stop(&mut self) {
+		self.player.pause();
+		self.playback_time = if self.return_to_start {self.play_start} else {f32::MIN};
+		if self.playback_time >= 0.0 {
+			self.player.seek(self.playback_time as f64);
+		}
+	}
 
-		let sound = self.noteblock_sounds[instrument as usize].clone() .speed(2.0_f32.powf((note as f32 - 66.0) / 12.0));
-		sink.append(sound);
+	fn update_song(&mut self) {
+		if self.song_changed {
+			self.player.set_song(Song::new(&self.project));
+			self.song_changed = false;
+		}
+	}
 
-		sink.detach();
+	// call this before every change to the project, once per gesture
+	fn checkpoint(&mut self) {
+		self.history.push(self.project.clone());
+		self.unsaved_changes = true;
+		self.song_changed = true;
+	}
+
+	// One undo step per text field or drag value edit instead of one per frame,
+	// restore puts the value from before this frame's change back into a copy of the project
+	fn track_edit(&mut self, response: &egui::Response, restore: impl FnOnce(&mut Project)) {
+		if response.changed() {
+			if self.editing != Some(response.id) {
+				let mut before = self.project.clone();
+				restore(&mut before);
+				self.history.push(before);
+				self.editing = Some(response.id);
+			}
+			self.unsaved_changes = true;
+			self.song_changed = true;
+		}
+		if (response.lost_focus() || response.drag_stopped()) && self.editing == Some(response.id) {
+			self.editing = None;
+		}
+	}
+
+	fn delete_selected_notes(&mut self) {
+		for layer in &mut self.project.layers {
+			layer.notes.retain(|note| !self.selected_notes.contains(&note.id));
+		}
+		self.selected_notes.clear();
+	}
+
+	fn for_selected_notes(&mut self, mut f: impl FnMut(&mut Note)) {
+		for layer in &mut self.project.layers {
+			layer.notes.iter_mut().filter(|note| self.selected_notes.contains(&note.id)).for_each(&mut f);
+		}
+	}
+
+	// Drops notes that got removed or locked from the selection
+	fn clean_selection(&mut self) {
+		let selectable: HashSet<NoteId> = self.project.layers.iter().filter(|layer| !layer.locked).flat_map(|layer| &layer.notes).map(|note| note.id).collect();
+		self.selected_notes.retain(|id| selectable.contains(id));
+	}
+
+	// Layers that box selection and Ctrl+A pick notes from, holding alt only picks from the current layer
+	fn selectable_layers(&self, current_layer_only: bool) -> Vec<usize> {
+		(0..self.project.layers.len())
+			.filter(|&index| !self.project.layers[index].locked)
+			.filter(|&index| index == self.current_layer || !(current_layer_only || self.only_current_layer))
+			.collect()
+	}
+
+	fn copy_selection(&mut self, ctx: &egui::Context) {
+		if self.selected_notes.is_empty() {
+			return;
+		}
+		let clipboard = Clipboard::new(self.project.layers.iter().enumerate()
+			.flat_map(|(index, layer)| layer.notes.iter().filter(|note| self.selected_notes.contains(&note.id)).map(move |note| (index, *note)))
+			.collect());
+		ctx.copy_text(clipboard.to_text());
+		self.clipboard = Some(clipboard);
+	}
+
+	fn cut_selection(&mut self, ctx: &egui::Context) {
+		self.copy_selection(ctx);
+		if !self.selected_notes.is_empty() {
+			self.checkpoint();
+			self.delete_selected_notes();
+		}
+	}
+
+	// Pasted notes become the selection
+	fn paste(&mut self, clipboard: &Clipboard, time: u32) {
+		let before = self.project.clone();
+		let mut changed = false;
+		self.selected_notes.clear();
+		for &(layer, mut note) in &clipboard.notes {
+			// notes from another project can have layers that don't exist here
+			let target = if self.paste_into_original_layers && layer < self.project.layers.len() {layer} else {self.current_layer};
+			if self.project.layers[target].locked {
+				continue;
+			}
+			note.time = note.time.saturating_add(time);
+			if self.project.layers[target].find_note(note.time, note.note).is_some() {
+				continue;
+			}
+			let id = self.project.add_note(target, note);
+			changed = true;
+			self.selected_notes.insert(id);
+		}
+		if changed {
+			self.history.push(before);
+			self.unsaved_changes = true;
+			self.song_changed = true;
+		}
+	}
+
+	// Moves the playback line to tick and scrolls there
+	fn jump_to(&mut self, tick: u32) {
+		self.playback_time = tick as f32;
+		self.player.seek(tick as f64);
+		self.scroll = (tick as f32 - 4.0).max(0.0);
+	}
+
+	// the next marker after the playback line, or the one before it
+	fn jump_to_marker(&mut self, next: bool) {
+		let now = if self.playback_time >= 0.0 {self.playback_time} else {-1.0};
+		let target = if next {
+			self.project.markers.iter().find(|marker| marker.tick as f32 > now)
+		} else {
+			self.project.markers.iter().rev().find(|marker| (marker.tick as f32) < now)
+		};
+		if let Some(tick) = target.map(|marker| marker.tick) {
+			self.jump_to(tick);
+		}
+	}
+
+	fn add_marker(&mut self) {
+		self.checkpoint();
+		let name = format!("Marker {}", self.project.markers.len() + 1);
+		self.project.add_marker(self.playback_line_time(), name);
+	}
+
+	// m:ss.s
+	fn format_seconds(seconds: f64) -> String {
+		// rounded before splitting so 59.96 s shows as 1:00.0 and not 0:60.0
+		let tenths = (seconds.max(0.0) * 10.0).round() as u64;
+		format!("{}:{:02}.{}", tenths / 600, tenths / 10 % 60, tenths % 10)
+	}
+
+	// where Edit > Paste puts notes, the start of the screen if the song is stopped
+	fn playback_line_time(&self) -> u32 {
+		if self.playback_time >= 0.0 {self.playback_time as u32} else {self.scroll.max(0.0) as u32}
+	}
+
+	// Moves the current layer along when layers get added, removed or moved, None is a removed layer
+	fn remap_layers(&mut self, map: impl Fn(usize) -> Option<usize>) {
+		self.current_layer = map(self.current_layer).unwrap_or(self.current_layer.min(self.project.layers.len() - 1));
+	}
+
+	fn layer_action(&mut self, action: LayerAction) {
+		if let LayerAction::Move(from, to) = action && (to == from || to == from + 1) {
+			return;
+		}
+		if let LayerAction::Delete(index) = action && self.project.layers[index].locked {
+			return;
+		}
+		self.checkpoint();
+		self.editing = None;
+		match action {
+			LayerAction::Duplicate(index) => {
+				let mut layer = self.project.layers[index].clone();
+				layer.name = format!("{} (copy)", layer.name);
+				for note in &mut layer.notes {
+					note.id = self.project.new_note_id();
+				}
+				self.project.layers.insert(index + 1, layer);
+				self.remap_layers(|layer| Some(if layer > index {layer + 1} else {layer}));
+				self.current_layer = index + 1;
+			}
+			LayerAction::Insert(index) => {
+				let mut layer = Layer::new(format!("Layer {}", self.project.layers.len() + 1), 0);
+				layer.color = project::layer_color(self.project.layers.len());
+				self.project.layers.insert(index, layer);
+				self.remap_layers(|layer| Some(if layer >= index {layer + 1} else {layer}));
+				self.current_layer = index;
+			}
+			LayerAction::Delete(index) => {
+				if self.project.layers.len() <= 1 {
+					return;
+				}
+				self.project.layers.remove(index);
+				self.clean_selection();
+				self.remap_layers(|layer| if layer == index {None} else if layer > index {Some(layer - 1)} else {Some(layer)});
+			}
+			LayerAction::Move(from, to) => {
+				let to = if to > from {to - 1} else {to};
+				let layer = self.project.layers.remove(from);
+				self.project.layers.insert(to, layer);
+				// where every layer ends up after the remove and then the insert
+				self.remap_layers(|layer| {
+					if layer == from {
+						return Some(to);
+					}
+					let layer = if layer > from {layer - 1} else {layer};
+					Some(if layer >= to {layer + 1} else {layer})
+				});
+			}
+		}
+	}
+
+	fn instrument_name(&self, instrument: u8) -> &str {
+		match self.project.custom_instrument(instrument) {
+			Some(custom) => &custom.name,
+			None => SOUND_FILE_NAMES.get(instrument as usize).copied().unwrap_or("?"),
+		}
+	}
+
+	// custom instrument sounds go after the built in ones in noteblock_sounds
+	fn reload_custom_sounds(&mut self) {
+		self.noteblock_sounds.truncate(FIRST_CUSTOM_INSTRUMENT as usize);
+		let project_dir = self.project_path.as_ref().and_then(|path| path.parent());
+		let mut errors: Vec<String> = Vec::new();
+		for custom in &self.project.custom_instruments {
+			match sounds::load_custom(custom, project_dir) {
+				Ok(sound) => self.noteblock_sounds.push(sound),
+				Err(error) => {
+					errors.push(format!("{}: {error}", custom.name));
+					self.noteblock_sounds.push(SamplesBuffer::new(1, 44100, vec![0.0]));
+				}
+			}
+		}
+		self.player.set_sounds(&self.noteblock_sounds);
+		self.song_changed = true;
+		if !errors.is_empty() {
+			self.error = Some(format!("Couldn't load some custom instruments\n\n{}", errors.join("\n")));
+		}
+	}
+
+	fn add_custom_instrument(&mut self) {
+		if self.project.custom_instruments.len() >= MAX_CUSTOM_INSTRUMENTS {
+			return;
+		}
+		if let Some(path) = rfd::FileDialog::new().set_title("Choose a sound").add_filter("Sounds", &["ogg", "wav"]).pick_file() {
+			let name = path.file_stem().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|| "Custom".to_string());
+			self.checkpoint();
+			self.project.custom_instruments.push(CustomInstrument::new(name, path.to_string_lossy().into_owned()));
+			self.reload_custom_sounds();
+		}
+	}
+
+	fn undo(&mut self) {
+		if let Some(replaced) = self.history.undo(&mut self.project) {
+			let sounds_changed = !replaced.same_custom_sounds(&self.project);
+			self.after_history_change(sounds_changed);
+		}
+	}
+
+	fn redo(&mut self) {
+		if let Some(replaced) = self.history.redo(&mut self.project) {
+			let sounds_changed = !replaced.same_custom_sounds(&self.project);
+			self.after_history_change(sounds_changed);
+		}
+	}
+
+	fn after_history_change(&mut self, sounds_changed: bool) {
+		self.clean_selection();
+		self.editing = None;
+		if self.current_layer >= self.project.layers.len() {
+			self.current_layer = self.project.layers.len() - 1;
+		}
+		self.unsaved_changes = true;
+		self.song_changed = true;
+		// decoding the sounds again is slow and cuts off the ones that are playing
+		if sounds_changed {
+			self.reload_custom_sounds();
+		}
 	}
 
 	fn reset(&mut self) {
@@ -95,39 +518,204 @@ impl App {
 		self.project_path = None;
 		self.current_layer = 0;
 		self.unsaved_changes = false;
+		self.selected_notes.clear();
+		self.history.clear();
+		self.reload_custom_sounds();
 
 		self.scroll = 0.0;
 		self.vscroll = 54.0;
 	}
 
 	fn open(&mut self) {
-		if let Some(path) = rfd::FileDialog::new().set_title("Choose project (THIS WILL DELETE YOUR CURRENT PROJECT IF IT'S NOT SAVED!!!)").add_filter("NoteBlockMusic files", &["nbm"]).pick_file() {
-			self.project_path = Some(path);
-			if let Some(path) = &self.project_path {
-				self.project = serde_json::from_reader(BufReader::new(File::open(path).expect("Failed to open file!"))).expect("Failed to load project!");
-				self.current_layer = 0;
-				self.unsaved_changes = false;
-				self.scroll = 0.0;
-				self.vscroll = 54.0;
+		if let Some(path) = rfd::FileDialog::new().set_title("Choose project (THIS WILL DELETE YOUR CURRENT PROJECT IF IT'S NOT SAVED!!!)")
+			.add_filter("All supported files", &["nbm", "nbs", "mid", "midi"])
+			.add_filter("NoteBlockMusic files", &["nbm"])
+			.add_filter("Note Block Studio files", &["nbs"])
+			.add_filter("MIDI files", &["mid", "midi"])
+			.pick_file() {
+			let has_extension = |extensions: &[&str]| path.extension().is_some_and(|ext| extensions.iter().any(|extension| ext.eq_ignore_ascii_case(extension)));
+			if has_extension(&["mid", "midi"]) {
+				// goes through the import dialog first
+				match read_file(&path, midi::read) {
+					Ok(tracks) => self.midi_import = Some(MidiImport::new(tracks, self.project.tempo.tps)),
+					Err(error) => self.error = Some(error),
+				}
+				return;
 			}
+			// only replace the current project once the file has loaded
+			if has_extension(&["nbs"]) {
+				match read_file(&path, nbs::read) {
+					Ok(mut project) => {
+						// the project won't be saved next to the .nbs, so the sounds need full paths
+						if let Some(dir) = path.parent() {
+							for custom in &mut project.custom_instruments {
+								if !custom.path.is_empty() && Path::new(&custom.path).is_relative() {
+									custom.path = dir.join(&custom.path).to_string_lossy().into_owned();
+								}
+							}
+						}
+						self.project = project;
+						self.project_path = None; // so saving doesn't overwrite the .nbs
+						self.unsaved_changes = true;
+					}
+					Err(error) => {
+						self.error = Some(error);
+						return;
+					}
+				}
+			} else {
+				match read_file(&path, |reader| Project::read(reader)) {
+					Ok(project) => {
+						self.project = project;
+						self.project.sort_notes();
+						self.project.assign_note_ids();
+						self.project_path = Some(path);
+						self.unsaved_changes = false;
+					}
+					Err(error) => {
+						self.error = Some(error);
+						return;
+					}
+				}
+			}
+			self.current_layer = 0;
+			self.selected_notes.clear();
+			self.history.clear();
+			self.reload_custom_sounds();
+			self.scroll = 0.0;
+			self.vscroll = 54.0;
 		}
 	}
 
 	fn save(&mut self) -> bool {
-		if let Some(path) = &self.project_path {
-			serde_json::to_writer(BufWriter::new(File::create(path).expect("Failed to open filke!")), &self.project).expect("Failed to save!!!");
-		} else {
-			if let Some(path) = rfd::FileDialog::new().set_title("Save new project").add_filter("NoteBlockMusic files", &["nbm"]).save_file() {
+		let path = match &self.project_path {
+			Some(path) => path.clone(),
+			None => match rfd::FileDialog::new().set_title("Save new project").add_filter("NoteBlockMusic files", &["nbm"]).save_file() {
+				Some(path) => path,
+				None => return false,
+			},
+		};
+		match write_file(&path, |writer| serde_json::to_writer(writer, &self.project).map_err(io::Error::from)) {
+			Ok(()) => {
 				self.project_path = Some(path);
-				if let Some(path) = &self.project_path {
-					serde_json::to_writer(BufWriter::new(File::create_new(path).expect("Failed to open filke!")), &self.project).expect("Failed to save!!!");
+				self.unsaved_changes = false;
+				true
+			}
+			Err(error) => {
+				self.error = Some(error);
+				false
+			}
+		}
+	}
+
+	fn finish_midi_import(&mut self, import: MidiImport) {
+		self.project = import.to_project();
+		self.project_path = None;
+		self.unsaved_changes = true;
+		self.current_layer = 0;
+		self.selected_notes.clear();
+		self.history.clear();
+		self.reload_custom_sounds();
+		self.scroll = 0.0;
+		self.vscroll = 54.0;
+	}
+
+	fn export_nbs(&mut self) {
+		if let Some(path) = rfd::FileDialog::new().set_title("Export to Note Block Studio").add_filter("Note Block Studio files", &["nbs"]).save_file() {
+			let report = match write_file(&path, |writer| nbs::write(writer, &self.project)) {
+				Ok(report) => report,
+				Err(error) => {
+					self.error = Some(error);
+					return;
 				}
-			} else {
-				return false;
+			};
+			let mut problems: Vec<String> = Vec::new();
+			if report.outside_noteblock_range > 0 || report.dropped > 0 {
+				problems.push(format!(
+					"{} notes are outside of the note block range (F#3 - F#5) and won't play in Minecraft.\n{} notes are outside of Note Block Studio's range (A0 - C8) and were left out.",
+					report.outside_noteblock_range, report.dropped
+				));
+			}
+			if report.tempo_changes > 0 {
+				problems.push(format!("Note Block Studio files only have one tempo, the {} tempo changes were left out.", report.tempo_changes));
+			}
+			if !problems.is_empty() {
+				self.message = Some(problems.join("\n"));
+			}
+		}
+	}
+
+	fn export_wav(&mut self) {
+		if let Some(path) = rfd::FileDialog::new().set_title("Export audio").add_filter("WAV files", &["wav"]).save_file() {
+			let samples = render::render(&self.project, &self.noteblock_sounds, self.wav_options.sample_rate);
+			if let Err(error) = write_file(&path, |writer| render::write_wav(writer, &samples, &self.wav_options)) {
+				self.error = Some(error);
+			}
+		}
+	}
+
+	fn export_schematic(&mut self) {
+		if let Some(path) = rfd::FileDialog::new().set_title("Export redstone schematic").add_filter("Sponge schematic files", &["schem"]).save_file() {
+			let report = match write_file(&path, |writer| schematic::write(writer, &self.project)) {
+				Ok(report) => report,
+				Err(error) => {
+					self.error = Some(error);
+					return;
+				}
+			};
+			let mut problems: Vec<String> = Vec::new();
+			for (tps, delay) in report.inexact_tempos {
+				problems.push(format!("{tps} TPS can't be made out of whole redstone ticks, the build plays it at {} TPS instead.", 10.0 / delay as f32));
+			}
+			if report.out_of_range > 0 {
+				problems.push(format!("{} notes are outside of the note block range (F#3 - F#5) and were left out.", report.out_of_range));
+			}
+			if report.cents > 0 {
+				problems.push(format!("{} notes have a fine pitch, note blocks can't play that so they play the nearest note.", report.cents));
+			}
+			if report.custom > 0 {
+				problems.push(format!("{} notes use custom instruments and were left out.", report.custom));
+			}
+			if report.dropped > 0 {
+				problems.push(format!("{} notes were left out because too many notes play at once.", report.dropped));
+			}
+			if !problems.is_empty() {
+				self.message = Some(problems.join("\n"));
+			}
+		}
+	}
+
+	fn export_datapack(&mut self) {
+		if let Some(path) = rfd::FileDialog::new().set_title("Export datapack").add_filter("Zip files", &["zip"]).save_file() {
+			let report = match write_file(&path, |writer| datapack::write(writer, &self.project, &self.datapack_options)) {
+				Ok(report) => report,
+				Err(error) => {
+					self.error = Some(error);
+					return;
+				}
+			};
+			let namespace = datapack::clean_namespace(&self.datapack_options.namespace);
+			let mut message = format!("Use /function {namespace}:play and /function {namespace}:stop in game.");
+			if report.out_of_range > 0 {
+				message += &format!("\n{} notes are outside of the note block range (F#3 - F#5) and were left out.", report.out_of_range);
+			}
+			if report.custom > 0 {
+				message += &format!("\n{} notes use custom instruments without a sound event and were left out.", report.custom);
+			}
+			self.message = Some(message);
+		}
+	}
+
+	fn export_midi(&mut self) {
+		if let Some(path) = rfd::FileDialog::new().set_title("Export MIDI").add_filter("MIDI files", &["mid"]).save_file() {
+			match write_file(&path, |writer| midi::write(writer, &self.project, &self.midi_export_options)) {
+				Ok(report) if report.cents > 0 => {
+					self.message = Some(format!("{} notes have a fine pitch, MIDI notes can't have one so they play the nearest key.", report.cents));
+				}
+				Ok(_) => {}
+				Err(error) => self.error = Some(error),
 			}
 		}
-		self.unsaved_changes = false;
-		true
 	}
 }
 
@@ -157,110 +745,243 @@ impl eframe::App for App {
 					if ui.button("Save").clicked() {
 						self.save();
 					}
+					ui.menu_button("Export", |ui| {
+						if ui.button("Note Block Studio (.nbs)").clicked() {
+							self.export_nbs();
+						}
+						if ui.button("WAV audio (.wav)").clicked() {
+							self.show_wav_export = true;
+						}
+						if ui.button("MIDI (.mid)").clicked() {
+							self.show_midi_export = true;
+						}
+						if ui.button("Redstone schematic (.schem)").clicked() {
+							self.export_schematic();
+						}
+						if ui.button("Datapack (.zip)").clicked() {
+							self.show_datapack_export = true;
+						}
+					});
 				});
 				ui.menu_button("Edit", |ui| {
-					if ui.button("Undo").clicked() {
-						
+					if ui.add_enabled(self.history.can_undo(), egui::Button::new("Undo").shortcut_text("Ctrl+Z")).clicked() {
+						self.undo();
+					}
+					if ui.add_enabled(self.history.can_redo(), egui::Button::new("Redo").shortcut_text("Ctrl+Shift+Z")).clicked() {
+						self.redo();
 					}
-					if ui.button("Redo").clicked() {
-						
+					ui.separator();
+					let has_selection = !self.selected_notes.is_empty();
+					if ui.add_enabled(has_selection, egui::Button::new("Cut").shortcut_text("Ctrl+X")).clicked() {
+						self.cut_selection(ctx);
+					}
+					if ui.add_enabled(has_selection, egui::Button::new("Copy").shortcut_text("Ctrl+C")).clicked() {
+						self.copy_selection(ctx);
+					}
+					if ui.add_enabled(self.clipboard.is_some(), egui::Button::new("Paste").shortcut_text("Ctrl+V")).on_hover_text("Pastes at the playback line").clicked()
+						&& let Some(clipboard) = self.clipboard.clone() {
+						self.paste(&clipboard, self.playback_line_time());
+					}
+					ui.checkbox(&mut self.paste_at_mouse, "Ctrl+V pastes at the mouse").on_hover_text("Otherwise at the playback line");
+					ui.checkbox(&mut self.paste_into_original_layers, "Paste into the original layers").on_hover_text("Otherwise into the current layer");
+				});
+				ui.menu_button("View", |ui| {
+					ui.checkbox(&mut self.color_by_instrument, "Color by instrument");
+					ui.checkbox(&mut self.only_current_layer, "Only show the current layer");
+				});
+				ui.menu_button("Playback", |ui| {
+					if ui.add(egui::Button::new("Play from the selection").shortcut_text("Shift+Space")).clicked() {
+						self.play_from_selection();
+					}
+					ui.add_enabled(self.loop_region.is_some(), egui::Checkbox::new(&mut self.looping, "Loop"));
+					if ui.add_enabled(self.loop_region.is_some(), egui::Button::new("Clear loop")).clicked() {
+						self.loop_region = None;
+						self.looping = false;
+					}
+					ui.checkbox(&mut self.return_to_start, "Stop goes back to where playing started");
+					ui.separator();
+					if ui.add(egui::Button::new("Add marker").shortcut_text("M")).clicked() {
+						self.add_marker();
+					}
+					if ui.add_enabled(!self.project.markers.is_empty(), egui::Button::new("Previous marker").shortcut_text("[")).clicked() {
+						self.jump_to_marker(false);
+					}
+					if ui.add_enabled(!self.project.markers.is_empty(), egui::Button::new("Next marker").shortcut_text("]")).clicked() {
+						self.jump_to_marker(true);
+					}
+					if ui.button("Markers...").clicked() {
+						self.show_markers = true;
 					}
 				});
 				if ui.input(|i| i.modifiers.ctrl && i.key_pressed(Key::S)) { // More convenient save button because why not
 					self.save();
 				}
+				if ctx.wants_keyboard_input() {
+					// let text fields handle their own undo
+				} else if ui.input(|i| i.modifiers.ctrl && !i.modifiers.shift && i.key_pressed(Key::Z)) {
+					self.undo();
+				} else if ui.input(|i| i.modifiers.ctrl && ((i.modifiers.shift && i.key_pressed(Key::Z)) || i.key_pressed(Key::Y))) {
+					self.redo();
+				}
 			});
 		});
 		egui::TopBottomPanel::top("toolbar").show(ctx, |ui| {
 			ui.add_space(5.0);
 			ui.horizontal(|ui| {
-				if ui.button(if self.playing {"⏸"} else {"▶"}).clicked() || ui.input(|i| i.key_pressed(Key::Space)) {
-					self.playing = !self.playing;
-					if self.playback_time < 0.0 {
-						self.playback_time = 0.0;
-						self.last_playback_time_tick = u32::MAX; // allow notes on beat 0 to play
-					}
+				// no shortcuts while typing in a text field
+				let typing = ctx.wants_keyboard_input();
+				if ui.button(if self.player.is_playing() {"⏸"} else {"▶"}).clicked() || (!typing && ui.input(|i| !i.modifiers.shift && i.key_pressed(Key::Space))) {
+					self.toggle_playback();
 				}
-				if ui.button("⏹").clicked() || ui.input(|i| i.key_pressed(Key::Enter)) {
-					self.playing = false;
-					self.playback_time = f32::MIN;
+				if !typing && ui.input(|i| i.modifiers.shift && i.key_pressed(Key::Space)) {
+					self.play_from_selection();
 				}
-				ui.add(egui::DragValue::new(&mut self.tps).speed(0.1).suffix(" TPS"));
+				if ui.button("⏹").clicked() || (!typing && ui.input(|i| i.key_pressed(Key::Enter))) {
+					self.stop();
+				}
+				if ui.add_enabled(self.loop_region.is_some(), egui::Button::selectable(self.looping, "🔁")).on_hover_text("Loop, drag in the ruler to set where").clicked() {
+					self.looping = !self.looping;
+				}
+				let old_tps = self.project.tempo.tps;
+				let response = ui.add(egui::DragValue::new(&mut self.project.tempo.tps).speed(0.1).range(MIN_TPS..=1000.0).suffix(" TPS")).on_hover_text("Tempo at the start of the song");
+				self.track_edit(&response, |project| project.tempo.tps = old_tps);
+				if ui.button("♩").on_hover_text("Tempo changes and time signature").clicked() {
+					self.show_tempo = true;
+				}
+				// where the playback line is and how long the song is
+				let position = self.playback_time.max(0.0);
+				let (bar, beat) = self.project.time_signature.bar_beat(position as u32);
+				let elapsed = self.project.tempo.seconds_at(position as f64);
+				let length = self.project.tempo.seconds_at(self.project.length() as f64);
+				ui.label(egui::RichText::new(format!(" {bar}:{beat}  {} / {} ", Self::format_seconds(elapsed), Self::format_seconds(length))).monospace());
 				ui.separator();
 				for i in 0..self.noteblock_sounds.len() as u8 {
-					let response = ui.button(SOUND_FILE_NAMES[i as usize]);
+					let response = ui.button(self.instrument_name(i));
 					if (if i == self.project.layers[self.current_layer].instrument {response.highlight()} else {response}).clicked() {
-						self.project.layers[self.current_layer].instrument = i;
-						self.unsaved_changes = true;
-						self.play_note(66, i);
+						// a locked layer keeps its instrument, the button still plays the sound
+						if i != self.project.layers[self.current_layer].instrument && !self.project.layers[self.current_layer].locked {
+							self.checkpoint();
+							self.project.layers[self.current_layer].instrument = i;
+						}
+						self.play_note(self.project.base_note(i) as f32, i, 1.0, 0.0);
 					}
 				}
+				if ui.button("+").on_hover_text("Custom instruments").clicked() {
+					self.show_custom_instruments = true;
+				}
 			});
 			ui.add_space(5.0);
 		});
-		egui::TopBottomPanel::bottom("layersbar").show(ctx, |ui| {
-			ui.add_space(10.0);
-			egui::ScrollArea::horizontal().show(ui, |ui| {
-				ui.horizontal(|ui| {
-					let mut to_delete: usize = usize::MAX;
-					for (index, layer) in self.project.layers.iter_mut().enumerate() {
-						let response = ui.add(egui::TextEdit::singleline(&mut layer.name).desired_width(100.0));
-						if (if index == self.current_layer {response.highlight()} else {response}).clicked() {
-							self.current_layer = index;
-							self.selected_notes.clear();
-						};
-						if ui.button("x").clicked() {
-							to_delete = index;
+		egui::SidePanel::left("layers").resizable(true).default_width(260.0).show(ctx, |ui| {
+			ui.add_space(5.0);
+			let mut action: Option<LayerAction> = None;
+			egui::ScrollArea::vertical().show(ui, |ui| {
+				for index in 0..self.project.layers.len() {
+					let fill = if index == self.current_layer {Color32::from_gray(50)} else {Color32::TRANSPARENT};
+					let row = egui::Frame::new().fill(fill).corner_radius(2.0).inner_margin(4.0).show(ui, |ui| {
+						ui.horizontal(|ui| {
+							ui.dnd_drag_source(ui.id().with(("layer_handle", index)), index, |ui| {
+								ui.label("☰");
+							}).response.on_hover_text("Drag to reorder");
+							let old_color = self.project.layers[index].color;
+							let response = egui::color_picker::color_edit_button_srgb(ui, &mut self.project.layers[index].color);
+							self.track_edit(&response, |project| project.layers[index].color = old_color);
+							let old_name = self.project.layers[index].name.clone();
+							let locked = self.project.layers[index].locked;
+							let response = ui.add(egui::TextEdit::singleline(&mut self.project.layers[index].name).desired_width(ui.available_width() - 50.0).interactive(!locked));
+							self.track_edit(&response, |project| project.layers[index].name = old_name);
+							// a locked name can't be edited but clicking it still picks the layer
+							let response = if locked {response.interact(egui::Sense::click())} else {response};
+							if (if index == self.current_layer {response.highlight()} else {response}).clicked() {
+								self.current_layer = index;
+							}
+							ui.weak(self.project.layers[index].notes.len().to_string()).on_hover_text("Notes");
+						});
+						ui.horizontal(|ui| {
+							let layer = &self.project.layers[index];
+							let (mute, solo, locked) = (layer.mute, layer.solo, layer.locked);
+							if ui.add(egui::Button::selectable(mute, "M")).on_hover_text("Mute").clicked() {
+								self.checkpoint();
+								self.project.layers[index].mute = !mute;
+							}
+							if ui.add(egui::Button::selectable(solo, "S")).on_hover_text("Solo").clicked() {
+								self.checkpoint();
+								self.project.layers[index].solo = !solo;
+							}
+							if ui.add(egui::Button::selectable(locked, "🔒")).on_hover_text("Lock, the notes can't be changed").clicked() {
+								self.checkpoint();
+								self.project.layers[index].locked = !locked;
+								self.clean_selection();
+							}
+							let old_volume = self.project.layers[index].volume;
+							ui.spacing_mut().slider_width = 60.0;
+							let response = ui.add(egui::Slider::new(&mut self.project.layers[index].volume, 0..=100).show_value(false)).on_hover_text(format!("Volume {old_volume}%"));
+							self.track_edit(&response, |project| project.layers[index].volume = old_volume);
+							let old_pan = self.project.layers[index].pan;
+							let response = ui.add(egui::DragValue::new(&mut self.project.layers[index].pan).range(-100..=100).prefix("pan ")).on_hover_text("Pan for every note on this layer, -100 is left and 100 is right");
+							self.track_edit(&response, |project| project.layers[index].pan = old_pan);
+							ui.menu_button("⋯", |ui| {
+								if ui.button("Duplicate").clicked() {
+									action = Some(LayerAction::Duplicate(index));
+								}
+								if ui.button("Insert above").clicked() {
+									action = Some(LayerAction::Insert(index));
+								}
+								if ui.button("Insert below").clicked() {
+									action = Some(LayerAction::Insert(index + 1));
+								}
+								let can_delete = self.project.layers.len() > 1 && !self.project.layers[index].locked;
+								if ui.add_enabled(can_delete, egui::Button::new("Delete")).on_disabled_hover_text("Locked layers and the last layer can't be deleted").clicked() {
+									action = Some(LayerAction::Delete(index));
+								}
+							});
+						});
+					}).response;
+
+					// dropping on the top half of a layer puts the dragged one above it, the bottom half below it
+					if let Some(pointer) = ui.input(|i| i.pointer.interact_pos()) {
+						let below = pointer.y > row.rect.center().y;
+						let line_y = if below {row.rect.bottom()} else {row.rect.top()};
+						if row.dnd_hover_payload::<usize>().is_some() {
+							ui.painter().hline(row.rect.x_range(), line_y, Stroke::new(2.0, Color32::from_rgb(0, 128, 255)));
 						}
-						ui.separator();
-					}
-					if to_delete != usize::MAX && self.project.layers.len() > 1 {
-						self.project.layers.remove(to_delete);
-						self.selected_notes.clear();
-						if self.current_layer >= to_delete {
-							self.current_layer = self.current_layer.saturating_sub(1);
+						if let Some(from) = row.dnd_release_payload::<usize>() {
+							action = Some(LayerAction::Move(*from, if below {index + 1} else {index}));
 						}
-						self.unsaved_changes = true;
 					}
-					if ui.button("+").clicked() {
-						self.current_layer = self.project.layers.len();
-						self.project.layers.push(Layer::new(format!("Layer {}", self.project.layers.len() + 1), 0));
-						self.selected_notes.clear();
-						self.unsaved_changes = true;
-					}
-				});
+				}
+				if ui.button("+").on_hover_text("Add layer").clicked() {
+					action = Some(LayerAction::Insert(self.project.layers.len()));
+				}
 			});
-			ui.add_space(10.0);
+			if let Some(action) = action {
+				self.layer_action(action);
+			}
 		});
 		egui::CentralPanel::default().show(ctx, |ui| {
+			let ruler_rect = {
+				let available = ui.available_rect_before_wrap();
+				Rect::from_min_size(pos2(available.left() + 50.0, available.top()), vec2(available.width() - 50.0, RULER_HEIGHT))
+			};
+			ui.add_space(RULER_HEIGHT);
 			ui.with_layout(egui::Layout::left_to_right(egui::Align::LEFT), |ui| {
 				ui.spacing_mut().item_spacing.x = 0.0;
 
-				let size = ui.available_size();
+				let available = ui.available_rect_before_wrap();
+				let lane_rect = Rect::from_min_max(pos2(available.left() + 50.0, available.bottom() - LANE_HEIGHT), available.max);
+				let size = ui.available_size() - vec2(0.0, LANE_HEIGHT);
 				let pitch_scale = size.y * 0.04; // 1.0 / 25.0 = 0.04
 				let time_scale = pitch_scale;
 				let input = ui.input(|i| i.to_owned());
 
 				{
-					// player
-					if self.playing {
-						self.playback_time += input.stable_dt * self.tps;
+					// player, the notes are played from the audio thread so this only keeps it up to date
+					self.player.set_loop(if self.looping {self.loop_region} else {None});
+					if self.player.is_playing() {
+						self.update_song();
+						self.playback_time = self.player.position() as f32;
+						ui.ctx().request_repaint();
 					}
-
-					let playback_tick = if self.playback_time < 0.0 {u32::MAX} else {self.playback_time as u32};
-
-					if playback_tick != self.last_playback_time_tick {
-						// Playback marker has crossed one of the beat lines
-						for layer in &self.project.layers {
-							for note in &layer.notes {
-								if note.time == playback_tick {
-									self.play_note(note.note, layer.instrument);
-								}
-							}
-						}
-					}
-
-					self.last_playback_time_tick = playback_tick;
 				}
 
 				{
@@ -280,7 +1001,8 @@ impl eframe::App for App {
 						if holding && let Some(mouse_pos) = input.pointer.interact_pos() && rect2.contains(mouse_pos) {
 							painter.rect_filled(rect2, 2.0, Color32::BLUE);
 							if note != self.last_played_note {
-								self.play_note(note, self.project.layers[self.current_layer].instrument);
+								let layer = &self.project.layers[self.current_layer];
+								self.play_note(note as f32, layer.instrument, layer.volume as f32 / 100.0, layer.pan as f32 / 100.0);
 								self.last_played_note = note;
 							}
 						} else {
@@ -295,6 +1017,8 @@ impl eframe::App for App {
 				{
 					// notes
 					let (rect, response) = ui.allocate_exact_size(vec2(size.x - 50.0, size.y), egui::Sense::click_and_drag());
+					// notes on a locked layer can still be heard, but not selected or changed
+					let locked = self.project.layers[self.current_layer].locked;
 					let painter = ui.painter_at(rect);
 	
 					let left = rect.left() - self.scroll * time_scale;
@@ -303,10 +1027,12 @@ impl eframe::App for App {
 					let top = bottom - pitch_scale * 128.0;
 	
 					// grid
+					let signature = self.project.time_signature;
+					let (ticks_per_beat, ticks_per_bar) = (signature.ticks_per_beat() as i32, signature.ticks_per_bar() as i32);
 					for beat in (self.scroll as i32 - 1)..=(self.scroll as i32 + 128) {
 						let x = left + (beat as f32 * time_scale);
 						painter.line_segment([pos2(x, top), pos2(x, bottom)], egui::Stroke::new(1.0, Color32::from_gray(
-							if beat % 16 == 0 {100} else if beat % 4 == 0 {60} else {40}
+							if beat % ticks_per_bar == 0 {100} else if beat % ticks_per_beat == 0 {60} else {40}
 						)));
 					}
 					for note in 0..=128 {
@@ -315,18 +1041,93 @@ impl eframe::App for App {
 							if note % 12 == 0 {100} else {40}
 						)));
 					}
+
+					let visible = self.scroll.max(0.0) as u32..(self.scroll + rect.width() / time_scale).max(0.0) as u32 + 1;
+
+					// ruler, drag to set the loop, click to move the playback line and right click to clear the loop.
+					// bar:beat labels go on the top half, markers and tempo changes on the bottom half
+					let ruler_response = ui.interact(ruler_rect, ui.id().with("ruler"), egui::Sense::click_and_drag());
+					let ruler_painter = ui.painter_at(ruler_rect);
+					ruler_painter.rect_filled(ruler_rect, 0.0, Color32::from_gray(30));
+					let pointer_tick = |pos: Pos2| ((pos.x - left) / time_scale).round().max(0.0) as u32;
+					if ruler_response.drag_started_by(egui::PointerButton::Primary) && let Some(origin) = input.pointer.press_origin() {
+						self.loop_drag_start = pointer_tick(origin);
+					}
+					if ruler_response.dragged_by(egui::PointerButton::Primary) && let Some(mouse_pos) = input.pointer.interact_pos() {
+						let (start, end) = (self.loop_drag_start, pointer_tick(mouse_pos));
+						if start != end {
+							self.loop_region = Some((start.min(end), start.max(end)));
+							self.looping = true;
+						}
+					} else if ruler_response.clicked_by(egui::PointerButton::Primary) && let Some(mouse_pos) = input.pointer.interact_pos() {
+						self.playback_time = ((mouse_pos.x - left) / time_scale).max(0.0);
+						self.player.seek(self.playback_time as f64);
+					} else if ruler_response.clicked_by(egui::PointerButton::Secondary) {
+						self.loop_region = None;
+						self.looping = false;
+					}
+					if let Some((start, end)) = self.loop_region {
+						let x_range = left + start as f32 * time_scale..=left + end as f32 * time_scale;
+						let color = if self.looping {Color32::from_rgb(0, 150, 80)} else {Color32::from_gray(70)};
+						ruler_painter.rect_filled(Rect::from_x_y_ranges(x_range.clone(), ruler_rect.y_range()), 2.0, color);
+						if self.looping {
+							painter.rect_filled(Rect::from_x_y_ranges(x_range, rect.y_range()), 0.0, Color32::from_rgba_unmultiplied(0, 150, 80, 20));
+						}
+					}
+					// every beat if there's room for it, otherwise every bar or every few bars
+					let label_every = if ticks_per_beat as f32 * time_scale >= 36.0 {
+						ticks_per_beat as u32
+					} else {
+						ticks_per_bar as u32 * (40.0 / (ticks_per_bar as f32 * time_scale)).ceil().max(1.0) as u32
+					};
+					let ruler_middle = ruler_rect.center().y;
+					for tick in (visible.start / label_every * label_every..visible.end).step_by(label_every as usize) {
+						let x = left + tick as f32 * time_scale;
+						let (bar, beat) = signature.bar_beat(tick);
+						let (text, line_bottom) = if beat == 1 {(bar.to_string(), ruler_middle)} else {(format!("{bar}:{beat}"), ruler_rect.top() + 5.0)};
+						ruler_painter.line_segment([pos2(x, ruler_rect.top()), pos2(x, line_bottom)], Stroke::new(1.0, Color32::from_gray(120)));
+						ruler_painter.text(pos2(x + 3.0, ruler_rect.top() + 1.0), egui::Align2::LEFT_TOP, text, egui::FontId::proportional(11.0), Color32::from_gray(200));
+					}
+					for marker in &self.project.markers {
+						let x = left + marker.tick as f32 * time_scale;
+						let color = Color32::from_rgb(90, 200, 255);
+						ruler_painter.line_segment([pos2(x, ruler_middle), pos2(x, ruler_rect.bottom())], Stroke::new(2.0, color));
+						ruler_painter.text(pos2(x + 3.0, ruler_rect.bottom()), egui::Align2::LEFT_BOTTOM, &marker.name, egui::FontId::proportional(10.0), color);
+						painter.line_segment([pos2(x, rect.top()), pos2(x, rect.bottom())], Stroke::new(1.0, color.gamma_multiply(0.4)));
+					}
+					for change in &self.project.tempo.changes {
+						let x = left + change.tick as f32 * time_scale;
+						let color = Color32::from_rgb(255, 160, 0);
+						ruler_painter.line_segment([pos2(x, ruler_middle), pos2(x, ruler_rect.bottom())], Stroke::new(2.0, color));
+						ruler_painter.text(pos2(x + 3.0, ruler_rect.bottom()), egui::Align2::LEFT_BOTTOM, format!("{} TPS", change.tps), egui::FontId::proportional(10.0), color);
+					}
+					if self.playback_time >= 0.0 {
+						let x = left + self.playback_time * time_scale;
+						ruler_painter.line_segment([pos2(x, ruler_rect.top()), pos2(x, ruler_rect.bottom())], Stroke::new(3.0, Color32::from_rgb(0, 128, 255)));
+					}
 					
-					// notes
+					// notes, only the ones on screen
 					for (index, layer) in self.project.layers.iter().enumerate() {
-						for (idx, note) in layer.notes.iter().enumerate() {
+						if self.only_current_layer && index != self.current_layer {
+							continue;
+						}
+						let [r, g, b] = if self.color_by_instrument {
+							INSTRUMENT_COLORS.get(layer.instrument as usize).copied().unwrap_or_else(|| project::layer_color(layer.instrument as usize))
+						} else {
+							layer.color
+						};
+						let tint = Color32::from_rgba_unmultiplied(r, g, b, if index == self.current_layer {255} else {128});
+						for note in layer.notes_between(visible.clone()) {
 							let x = left + (note.time as f32 * time_scale);
 							let y = bottom - ((note.note as f32) * pitch_scale);
 							let rect2 = Rect::from_min_size(pos2(x, y - pitch_scale), vec2(time_scale, pitch_scale));
-							painter.image(self.noteblock_texture.id(), rect2, Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0)),
-								if index == self.current_layer {Color32::WHITE}
-								else {Color32::from_rgba_unmultiplied( 255, 255, 255, 128 )}
-							);
-							if index == self.current_layer && self.selected_notes.contains(&idx) {
+							painter.image(self.noteblock_texture.id(), rect2, Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0)), tint);
+							// fine pitch marker, up for sharp and down for flat
+							if note.cents != 0 {
+								let marker_y = if note.cents > 0 {rect2.top() + 3.0} else {rect2.bottom() - 3.0};
+								painter.circle_filled(pos2(rect2.right() - 3.0, marker_y), 2.5, Color32::from_rgb(255, 160, 0));
+							}
+							if self.selected_notes.contains(&note.id) {
 								painter.rect_stroke(rect2, 2.0, Stroke::new(2.0, Color32::WHITE), egui::StrokeKind::Inside);
 							}
 						}
@@ -343,6 +1144,7 @@ impl eframe::App for App {
 						// self.scroll -= response.drag_delta().x / time_scale;
 						if let Some(mouse_pos) = input.pointer.interact_pos() {
 							self.playback_time = ( mouse_pos.x - left ) / time_scale;
+							self.player.seek(self.playback_time.max(0.0) as f64);
 						}
 					}
 					if response.clicked_by(egui::PointerButton::Primary) {
@@ -351,21 +1153,22 @@ impl eframe::App for App {
 							let time = (( mouse_pos.x - left ) / time_scale) as u32;
 							let note = (( bottom - mouse_pos.y ) / pitch_scale) as u8;
 							self.selected_notes.clear();
-							if self.project.layers[self.current_layer].notes.iter().find(|x| x.time == time && x.note == note).is_none() {
-								self.project.layers[self.current_layer].notes.push(project::Note::new( time, note ));
+							if !locked && self.project.layers[self.current_layer].find_note(time, note).is_none() {
+								self.checkpoint();
+								self.project.add_note(self.current_layer, Note::new( time, note ));
 							};
-							self.play_note(note, self.project.layers[self.current_layer].instrument);
-							self.unsaved_changes = true;
+							let layer = &self.project.layers[self.current_layer];
+							self.play_note(note as f32, layer.instrument, layer.volume as f32 / 100.0, layer.pan as f32 / 100.0);
 						}
 					} else if response.clicked_by(egui::PointerButton::Secondary) {
 						if let Some(mouse_pos) = input.pointer.interact_pos() {
 							let time = (( mouse_pos.x - left ) / time_scale) as u32;
 							let note = (( bottom - mouse_pos.y ) / pitch_scale) as u8;
 							self.selected_notes.clear();
-							if let Some(found) = self.project.layers[self.current_layer].notes.iter_mut().position(|x| x.time == time && x.note == note) {
+							if !locked && let Some(found) = self.project.layers[self.current_layer].find_note(time, note) {
+								self.checkpoint();
 								self.project.layers[self.current_layer].notes.remove(found);
 							}
-							self.unsaved_changes = true;
 						}
 					}
 					if response.dragged_by(egui::PointerButton::Primary) {
@@ -380,12 +1183,14 @@ impl eframe::App for App {
 						}
 					} else if response.drag_stopped_by(egui::PointerButton::Primary) {
 						let selection_rect = Rect::from_points(&[self.selection_start, self.selection_end]);
-						for (index, note) in self.project.layers[self.current_layer].notes.iter().enumerate() {
-							let x = note.time as f32 * time_scale;
-							let y = (note.note as f32) * pitch_scale;
-							let rect2 = Rect::from_min_size(pos2(x, y - pitch_scale), vec2(time_scale, pitch_scale));
-							if !self.selected_notes.contains(&index) && rect2.intersects(selection_rect) {
-								self.selected_notes.push(index);
+						for layer in self.selectable_layers(input.modifiers.alt) {
+							for note in &self.project.layers[layer].notes {
+								let x = note.time as f32 * time_scale;
+								let y = (note.note as f32) * pitch_scale;
+								let rect2 = Rect::from_min_size(pos2(x, y - pitch_scale), vec2(time_scale, pitch_scale));
+								if rect2.intersects(selection_rect) {
+									self.selected_notes.insert(note.id);
+								}
 							}
 						}
 					}
@@ -393,57 +1198,131 @@ impl eframe::App for App {
 						self.scroll = 0.0;
 					}
 
-					if input.modifiers.ctrl && input.key_pressed(Key::A) {
-						self.selected_notes = self.project.layers[self.current_layer].notes.iter().enumerate().map(|(index, _)| index).collect();
-					} else if input.key_pressed(Key::Escape) {
+					// while a text field has focus the keys below are for it
+					let typing = ui.ctx().wants_keyboard_input();
+					if !typing && input.modifiers.ctrl && input.key_pressed(Key::A) {
+						self.selected_notes = self.selectable_layers(input.modifiers.alt).into_iter()
+							.flat_map(|layer| self.project.layers[layer].notes.iter().map(|note| note.id))
+							.collect();
+					} else if !typing && input.key_pressed(Key::Escape) {
 						self.selected_notes.clear();
 					}
-					if input.modifiers.ctrl && input.key_pressed(Key::D) {
-						for index in &mut self.selected_notes {
-							let mut note = self.project.layers[self.current_layer].notes[*index];
-							note.time += 2;
-							note.note += 2;
-							self.project.layers[self.current_layer].notes.push(note);
-							*index = self.project.layers[self.current_layer].notes.len() - 1;
+
+					// egui turns Ctrl+C, Ctrl+X and Ctrl+V into these events, pasting reads the system clipboard
+					if !typing {
+						for event in &input.events {
+							match event {
+								egui::Event::Copy => self.copy_selection(ui.ctx()),
+								egui::Event::Cut => self.cut_selection(ui.ctx()),
+								egui::Event::Paste(text) => if let Some(clipboard) = Clipboard::from_text(text) {
+									let time = match response.hover_pos() {
+										Some(mouse_pos) if self.paste_at_mouse => ((mouse_pos.x - left) / time_scale).max(0.0) as u32,
+										_ => self.playback_line_time(),
+									};
+									self.paste(&clipboard, time);
+									self.clipboard = Some(clipboard);
+								},
+								_ => {}
+							}
 						}
-						self.unsaved_changes = true;
 					}
-					if input.key_pressed(Key::Delete) {
-						self.selected_notes.sort();
-						for index in self.selected_notes.iter().rev() {
-							self.project.layers[self.current_layer].notes.remove(*index);
-						}
-						self.selected_notes.clear();
-						self.unsaved_changes = true;
+					let has_selection = !typing && !self.selected_notes.is_empty();
+					let nudge = [Key::ArrowRight, Key::ArrowLeft, Key::ArrowUp, Key::ArrowDown].iter().any(|key| input.key_pressed(*key));
+					if has_selection && ((input.modifiers.ctrl && input.key_pressed(Key::D)) || input.key_pressed(Key::Delete) || nudge) {
+						// duplicate, delete and every nudge are a single undo step each
+						self.checkpoint();
 					}
-					if input.key_pressed(Key::ArrowRight) {
-						for index in &self.selected_notes {
-							self.project.layers[self.current_layer].notes[*index].time = self.project.layers[self.current_layer].notes[*index].time.saturating_add(1);
+					if has_selection && input.modifiers.ctrl && input.key_pressed(Key::D) {
+						// the copies become the selection
+						let mut copies: HashSet<NoteId> = HashSet::new();
+						for layer in 0..self.project.layers.len() {
+							let notes: Vec<Note> = self.project.layers[layer].notes.iter().filter(|note| self.selected_notes.contains(&note.id)).copied().collect();
+							for mut note in notes {
+								note.time += 2;
+								note.note += 2;
+								copies.insert(self.project.add_note(layer, note));
+							}
 						}
-						self.unsaved_changes = true;
+						self.selected_notes = copies;
 					}
-					if input.key_pressed(Key::ArrowLeft) {
-						for index in &self.selected_notes {
-							self.project.layers[self.current_layer].notes[*index].time = self.project.layers[self.current_layer].notes[*index].time.saturating_sub(1);
+					if has_selection && input.key_pressed(Key::Delete) {
+						self.delete_selected_notes();
+					}
+					if has_selection && input.key_pressed(Key::ArrowRight) {
+						self.for_selected_notes(|note| note.time = note.time.saturating_add(1));
+						self.project.sort_notes();
+					}
+					if has_selection && input.key_pressed(Key::ArrowLeft) {
+						self.for_selected_notes(|note| note.time = note.time.saturating_sub(1));
+						self.project.sort_notes();
+					}
+					if has_selection && input.key_pressed(Key::ArrowUp) {
+						self.for_selected_notes(|note| note.note = note.note.saturating_add(1));
+					}
+					if has_selection && input.key_pressed(Key::ArrowDown) {
+						self.for_selected_notes(|note| note.note = note.note.saturating_sub(1));
+					}
+
+					if !typing {
+						if input.key_pressed(Key::R) {
+							self.scroll = 0.0;
+							self.vscroll = 54.0;
+						}
+						if input.key_pressed(Key::OpenBracket) {
+							self.jump_to_marker(false);
+						} else if input.key_pressed(Key::CloseBracket) {
+							self.jump_to_marker(true);
+						} else if input.modifiers.is_none() && input.key_pressed(Key::M) {
+							self.add_marker();
 						}
-						self.unsaved_changes = true;
 					}
-					if input.key_pressed(Key::ArrowUp) {
-						for index in &self.selected_notes {
-							self.project.layers[self.current_layer].notes[*index].note = self.project.layers[self.current_layer].notes[*index].note.saturating_add(1);
+
+					// note property lane, edits the current layer's notes under the mouse, or only the selected ones if there is a selection
+					let lane_response = ui.interact(lane_rect, ui.id().with("lane"), egui::Sense::click_and_drag());
+					let lane_painter = ui.painter_at(lane_rect);
+					lane_painter.rect_filled(lane_rect, 0.0, Color32::from_gray(25));
+					lane_painter.line_segment([lane_rect.left_top(), lane_rect.right_top()], Stroke::new(1.0, Color32::from_gray(60)));
+					for (index, lane) in Lane::ALL.into_iter().enumerate() {
+						let button_rect = Rect::from_min_size(pos2(lane_rect.left() - 50.0, lane_rect.top() + 2.0 + index as f32 * 19.0), vec2(48.0, 17.0));
+						if ui.put(button_rect, egui::Button::selectable(self.lane == lane, lane.name()).small()).clicked() {
+							self.lane = lane;
 						}
-						self.unsaved_changes = true;
 					}
-					if input.key_pressed(Key::ArrowDown) {
-						for index in &self.selected_notes {
-							self.project.layers[self.current_layer].notes[*index].note = self.project.layers[self.current_layer].notes[*index].note.saturating_sub(1);
+					let lane = self.lane;
+					let lane_y = |value: f32| lane_rect.bottom() - 2.0 - (lane_rect.height() - 4.0) * value;
+					if lane.base() != 0.0 {
+						lane_painter.line_segment([pos2(lane_rect.left(), lane_y(0.5)), pos2(lane_rect.right(), lane_y(0.5))], Stroke::new(1.0, Color32::from_gray(60)));
+					}
+					for note in self.project.layers[self.current_layer].notes_between(visible.clone()) {
+						let x = left + (note.time as f32 + 0.5) * time_scale;
+						let y = lane_y(lane.get(note));
+						let color = if self.selected_notes.contains(&note.id) {Color32::WHITE} else {Color32::from_rgb(0, 128, 255)};
+						lane_painter.line_segment([pos2(x, lane_y(lane.base())), pos2(x, y)], Stroke::new(2.0, color));
+						lane_painter.circle_filled(pos2(x, y), 3.0, color);
+					}
+					if !locked && (lane_response.dragged_by(egui::PointerButton::Primary) || lane_response.clicked_by(egui::PointerButton::Primary)) && let Some(mouse_pos) = input.pointer.interact_pos() {
+						let time = ((mouse_pos.x - left) / time_scale) as u32;
+						let value = ((lane_rect.bottom() - 2.0 - mouse_pos.y) / (lane_rect.height() - 4.0)).clamp(0.0, 1.0);
+						lane_painter.text(mouse_pos, egui::Align2::LEFT_BOTTOM, lane.format(value), egui::FontId::default(), Color32::WHITE);
+						let targets: Vec<usize> = self.project.layers[self.current_layer].notes.iter().enumerate()
+							.filter(|&(_, note)| note.time == time && (self.selected_notes.is_empty() || self.selected_notes.contains(&note.id)))
+							.map(|(index, _)| index).collect();
+						for index in targets {
+							let mut note = self.project.layers[self.current_layer].notes[index];
+							lane.set(&mut note, value);
+							if lane.get(&note) != lane.get(&self.project.layers[self.current_layer].notes[index]) {
+								// one undo step per drag
+								if self.editing != Some(lane_response.id) {
+									self.checkpoint();
+									self.editing = Some(lane_response.id);
+								}
+								self.song_changed = true;
+								self.project.layers[self.current_layer].notes[index] = note;
+							}
 						}
-						self.unsaved_changes = true;
 					}
-
-					if input.key_pressed(Key::R) {
-						self.scroll = 0.0;
-						self.vscroll = 54.0;
+					if (lane_response.drag_stopped() || lane_response.clicked()) && self.editing == Some(lane_response.id) {
+						self.editing = None;
 					}
 				}
 			});
@@ -490,6 +1369,322 @@ impl eframe::App for App {
 			});
 		}
 
+		if self.show_wav_export {
+			egui::Window::new("Export WAV").collapsible(false).resizable(false).show(ctx, |ui| {
+				egui::ComboBox::from_label("Sample rate").selected_text(format!("{} Hz", self.wav_options.sample_rate)).show_ui(ui, |ui| {
+					for rate in [22050, 44100, 48000, 96000] {
+						ui.selectable_value(&mut self.wav_options.sample_rate, rate, format!("{rate} Hz"));
+					}
+				});
+				egui::ComboBox::from_label("Bit depth").selected_text(self.wav_options.bit_depth.name()).show_ui(ui, |ui| {
+					for depth in BitDepth::ALL {
+						ui.selectable_value(&mut self.wav_options.bit_depth, depth, depth.name());
+					}
+				});
+				ui.checkbox(&mut self.wav_options.normalize, "Normalize");
+				ui.horizontal(|ui| {
+					if ui.button("Export").clicked() {
+						self.show_wav_export = false;
+						self.export_wav();
+					}
+					if ui.button("Cancel").clicked() {
+						self.show_wav_export = false;
+					}
+				});
+			});
+		}
+		if self.show_midi_export {
+			egui::Window::new("Export MIDI").collapsible(false).resizable(false).show(ctx, |ui| {
+				ui.add(egui::DragValue::new(&mut self.midi_export_options.note_length).speed(0.05).range(0.05..=64.0).prefix("Note length ").suffix(" ticks"));
+				ui.horizontal(|ui| {
+					if ui.button("Export").clicked() {
+						self.show_midi_export = false;
+						self.export_midi();
+					}
+					if ui.button("Cancel").clicked() {
+						self.show_midi_export = false;
+					}
+				});
+			});
+		}
+		if self.show_custom_instruments {
+			let mut open = true;
+			egui::Window::new("Custom instruments").open(&mut open).show(ctx, |ui| {
+				let mut to_delete: usize = usize::MAX;
+				let mut reload = false;
+				egui::Grid::new("custom_instruments").striped(true).show(ui, |ui| {
+					ui.label("Name");
+					ui.label("Base note");
+					ui.label("Sound event");
+					ui.label("Sound");
+					ui.end_row();
+					for index in 0..self.project.custom_instruments.len() {
+						let old_name = self.project.custom_instruments[index].name.clone();
+						let response = ui.add(egui::TextEdit::singleline(&mut self.project.custom_instruments[index].name).desired_width(100.0));
+						self.track_edit(&response, |project| project.custom_instruments[index].name = old_name);
+
+						let old_base_note = self.project.custom_instruments[index].base_note;
+						let response = ui.add(egui::DragValue::new(&mut self.project.custom_instruments[index].base_note).range(0..=127).custom_formatter(|note, _| Self::get_note_name(note as u8)));
+						self.track_edit(&response, |project| project.custom_instruments[index].base_note = old_base_note);
+
+						let old_sound_event = self.project.custom_instruments[index].sound_event.clone();
+						let response = ui.add(egui::TextEdit::singleline(&mut self.project.custom_instruments[index].sound_event).hint_text("for datapacks").desired_width(150.0));
+						self.track_edit(&response, |project| project.custom_instruments[index].sound_event = old_sound_event);
+
+						ui.horizontal(|ui| {
+							let custom = &self.project.custom_instruments[index];
+							if custom.data.is_some() {
+								ui.label("Embedded");
+								if ui.button("Unembed").on_hover_text("Load the sound from its file again").clicked() {
+									self.checkpoint();
+									self.project.custom_instruments[index].data = None;
+									reload = true;
+								}
+							} else {
+								ui.label(&custom.path);
+								if ui.button("Embed").on_hover_text("Save the sound in the project file").clicked() {
+									let project_dir = self.project_path.as_ref().and_then(|path| path.parent());
+									let path = project_dir.map(|dir| dir.join(&custom.path)).unwrap_or_else(|| PathBuf::from(&custom.path));
+									match std::fs::read(&path) {
+										Ok(data) => {
+											self.checkpoint();
+											self.project.custom_instruments[index].data = Some(data.into());
+										}
+										Err(error) => self.error = Some(format!("Couldn't open {}\n\n{error}", path.display())),
+									}
+								}
+							}
+							if ui.button("▶").clicked() {
+								let instrument = FIRST_CUSTOM_INSTRUMENT + index as u8;
+								self.play_note(self.project.base_note(instrument) as f32, instrument, 1.0, 0.0);
+							}
+							if ui.button("x").clicked() {
+								to_delete = index;
+							}
+						});
+						ui.end_row();
+					}
+				});
+				if to_delete != usize::MAX {
+					self.checkpoint();
+					self.project.remove_custom_instrument(to_delete);
+					reload = true;
+				}
+				let can_add = self.project.custom_instruments.len() < MAX_CUSTOM_INSTRUMENTS;
+				if ui.add_enabled(can_add, egui::Button::new("Add sound...")).on_disabled_hover_text(format!("Projects can have up to {MAX_CUSTOM_INSTRUMENTS} custom instruments")).clicked() {
+					self.add_custom_instrument();
+				}
+				if reload {
+					self.reload_custom_sounds();
+				}
+			});
+			self.show_custom_instruments = open;
+		}
+		if self.show_tempo {
+			let mut open = true;
+			egui::Window::new("Tempo").open(&mut open).show(ctx, |ui| {
+				ui.horizontal(|ui| {
+					ui.label("Time signature");
+					let old_signature = self.project.time_signature;
+					let response = ui.add(egui::DragValue::new(&mut self.project.time_signature.beats_per_bar).range(1..=32).suffix(" beats per bar"));
+					self.track_edit(&response, |project| project.time_signature = old_signature);
+					let response = ui.add(egui::DragValue::new(&mut self.project.time_signature.ticks_per_beat).range(1..=32).suffix(" ticks per beat"));
+					self.track_edit(&response, |project| project.time_signature = old_signature);
+				});
+				ui.separator();
+				let mut to_delete: usize = usize::MAX;
+				let mut sort = false;
+				egui::Grid::new("tempo_changes").striped(true).show(ui, |ui| {
+					ui.label("Tick");
+					ui.label("TPS");
+					ui.end_row();
+					for index in 0..self.project.tempo.changes.len() {
+						let old_tick = self.project.tempo.changes[index].tick;
+						let response = ui.add(egui::DragValue::new(&mut self.project.tempo.changes[index].tick).range(1..=u32::MAX));
+						self.track_edit(&response, |project| project.tempo.changes[index].tick = old_tick);
+						// only reorder once the tick is done being edited
+						sort |= response.drag_stopped() || response.lost_focus();
+						let old_tps = self.project.tempo.changes[index].tps;
+						let response = ui.add(egui::DragValue::new(&mut self.project.tempo.changes[index].tps).speed(0.1).range(MIN_TPS..=1000.0));
+						self.track_edit(&response, |project| project.tempo.changes[index].tps = old_tps);
+						if ui.button("x").clicked() {
+							to_delete = index;
+						}
+						ui.end_row();
+					}
+				});
+				if to_delete != usize::MAX {
+					self.checkpoint();
+					self.project.tempo.changes.remove(to_delete);
+				}
+				if sort {
+					self.project.tempo.sort();
+					self.song_changed = true;
+				}
+				if ui.button("Add at the playback line").clicked() {
+					let tick = self.playback_line_time();
+					let tps = self.project.tempo.tps_at(tick as f64);
+					self.checkpoint();
+					self.project.tempo.set(tick, tps);
+				}
+			});
+			self.show_tempo = open;
+		}
+		if self.show_markers {
+			let mut open = true;
+			egui::Window::new("Markers").open(&mut open).show(ctx, |ui| {
+				let mut to_delete: usize = usize::MAX;
+				let mut jump: Option<u32> = None;
+				let mut sort = false;
+				egui::Grid::new("markers").striped(true).show(ui, |ui| {
+					ui.label("Name");
+					ui.label("Tick");
+					ui.end_row();
+					for index in 0..self.project.markers.len() {
+						let old_name = self.project.markers[index].name.clone();
+						let response = ui.add(egui::TextEdit::singleline(&mut self.project.markers[index].name).desired_width(120.0));
+						self.track_edit(&response, |project| project.markers[index].name = old_name);
+						let old_tick = self.project.markers[index].tick;
+						let response = ui.add(egui::DragValue::new(&mut self.project.markers[index].tick));
+						self.track_edit(&response, |project| project.markers[index].tick = old_tick);
+						sort |= response.drag_stopped() || response.lost_focus();
+						if ui.button("Go").clicked() {
+							jump = Some(self.project.markers[index].tick);
+						}
+						if ui.button("x").clicked() {
+							to_delete = index;
+						}
+						ui.end_row();
+					}
+				});
+				if to_delete != usize::MAX {
+					self.checkpoint();
+					self.project.markers.remove(to_delete);
+				}
+				if sort {
+					self.project.markers.sort_by_key(|marker| marker.tick);
+				}
+				if let Some(tick) = jump {
+					self.jump_to(tick);
+				}
+				if ui.button("Add at the playback line").clicked() {
+					self.add_marker();
+				}
+			});
+			self.show_markers = open;
+		}
+		if self.show_datapack_export {
+			egui::Window::new("Export datapack").collapsible(false).resizable(false).show(ctx, |ui| {
+				egui::Grid::new("datapack_options").show(ui, |ui| {
+					ui.label("Namespace");
+					ui.text_edit_singleline(&mut self.datapack_options.namespace);
+					ui.end_row();
+					ui.label("Players");
+					ui.text_edit_singleline(&mut self.datapack_options.selector);
+					ui.end_row();
+					ui.label("Pack format");
+					ui.horizontal(|ui| {
+						ui.add(egui::DragValue::new(&mut self.datapack_options.pack_format).range(1..=1000));
+						let version = datapack::PACK_FORMAT_PRESETS.iter().find(|preset| preset.1 == self.datapack_options.pack_format).map(|preset| preset.0).unwrap_or("Custom");
+						egui::ComboBox::from_id_salt("pack_format_preset").selected_text(version).show_ui(ui, |ui| {
+							for (name, format) in datapack::PACK_FORMAT_PRESETS {
+								ui.selectable_value(&mut self.datapack_options.pack_format, format, name);
+							}
+						});
+					});
+					ui.end_row();
+				});
+				ui.horizontal(|ui| {
+					if ui.button("Export").clicked() {
+						self.show_datapack_export = false;
+						self.export_datapack();
+					}
+					if ui.button("Cancel").clicked() {
+						self.show_datapack_export = false;
+					}
+				});
+			});
+		}
+		if let Some(import) = &mut self.midi_import {
+			let mut finish = false;
+			let mut cancel = false;
+			egui::Window::new("Import MIDI").collapsible(false).show(ctx, |ui| {
+				ui.add(egui::DragValue::new(&mut import.tps).speed(0.1).range(MIN_TPS..=1000.0).prefix("Quantize to ").suffix(" TPS"));
+				ui.separator();
+				egui::ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+					egui::Grid::new("midi_tracks").striped(true).show(ui, |ui| {
+						ui.label("Layer");
+						ui.label("Instrument");
+						ui.label("Notes");
+						ui.label("Out of range");
+						ui.label("Transpose");
+						ui.end_row();
+						for index in 0..import.tracks.len() {
+							ui.label(&import.tracks[index].name);
+							ui.label(SOUND_FILE_NAMES[import.tracks[index].instrument as usize]);
+							ui.label(import.tracks[index].notes.len().to_string());
+							let out_of_range = import.out_of_range(index);
+							ui.colored_label(if out_of_range > 0 {Color32::YELLOW} else {Color32::GRAY}, out_of_range.to_string());
+							ui.horizontal(|ui| {
+								ui.add(egui::DragValue::new(&mut import.transpose[index]).range(-127..=127).suffix(" st"));
+								if ui.button("-12").clicked() {
+									import.transpose[index] -= 12;
+								}
+								if ui.button("+12").clicked() {
+									import.transpose[index] += 12;
+								}
+								if ui.button("Auto").clicked() {
+									import.auto_transpose(index);
+								}
+							});
+							ui.end_row();
+						}
+					});
+				});
+				ui.separator();
+				ui.horizontal(|ui| {
+					if ui.button("Import").clicked() {
+						finish = true;
+					}
+					if ui.button("Cancel").clicked() {
+						cancel = true;
+					}
+				});
+			});
+			if finish {
+				if let Some(import) = self.midi_import.take() {
+					self.finish_midi_import(import);
+				}
+			} else if cancel {
+				self.midi_import = None;
+			}
+		}
+		if let Some(message) = &self.message {
+			let mut close = false;
+			egui::Window::new("Note Block Music").collapsible(false).resizable(false).show(ctx, |ui| {
+				ui.label(message);
+				if ui.button("OK").clicked() {
+					close = true;
+				}
+			});
+			if close {
+				self.message = None;
+			}
+		}
+
+		if let Some(error) = &self.error {
+			let mut close = false;
+			egui::Window::new("Error").collapsible(false).resizable(false).show(ctx, |ui| {
+				ui.colored_label(Color32::LIGHT_RED, error);
+				if ui.button("OK").clicked() {
+					close = true;
+				}
+			});
+			if close {
+				self.error = None;
+			}
+		}
+
 		ctx.send_viewport_cmd(egui::ViewportCommand::Title(if self.unsaved_changes {"Note Block Music*".to_string()} else {"Note Block Music".to_string()}));
 	}
 }
\ No newline at end of file
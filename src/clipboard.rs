@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+use crate::project::Note;
+
+// first line of the clipboard text, so pasting random text doesn't do anything
+const HEADER: &str = "NoteBlockMusic notes";
+
+// Copied notes, goes through the system clipboard as text so it works between windows
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Clipboard {
+	// (layer, note), times start at 0
+	pub notes: Vec<(usize, Note)>,
+}
+
+impl Clipboard {
+	pub fn new(mut notes: Vec<(usize, Note)>) -> Self {
+		let start = notes.iter().map(|(_, note)| note.time).min().unwrap_or(0);
+		for (_, note) in &mut notes {
+			note.time -= start;
+		}
+		Self { notes }
+	}
+
+	pub fn to_text(&self) -> String {
+		format!("{HEADER}\n{}", serde_json::to_string(self).unwrap_or_default())
+	}
+
+	pub fn from_text(text: &str) -> Option<Self> {
+		let (header, json) = text.split_once('\n')?;
+		if header.trim() != HEADER {
+			return None;
+		}
+		serde_json::from_str(json).ok()
+	}
+}
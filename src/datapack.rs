@@ -0,0 +1,202 @@
+// Datapack export, plays the song with /playsound from a scoreboard tick counter
+use std::io::{self, Write};
+
+use flate2::{Compression, Crc, write::DeflateEncoder};
+
+use crate::{app::MINECRAFT_INSTRUMENT_NAMES, project::Project, render};
+
+// 1.21 renamed the functions folders to function
+const SINGULAR_FOLDERS_PACK_FORMAT: u32 = 45;
+
+pub const PACK_FORMAT_PRESETS: [(&str, u32); 5] = [("1.20.1", 15), ("1.20.4", 26), ("1.20.6", 41), ("1.21.1", 48), ("1.21.4", 61)];
+
+pub struct DatapackOptions {
+	pub namespace: String,
+	pub selector: String,
+	pub pack_format: u32,
+}
+
+impl DatapackOptions {
+	pub fn new() -> Self {
+		Self { namespace: "song".to_string(), selector: "@a".to_string(), pack_format: 48 }
+	}
+}
+
+pub struct DatapackReport {
+	pub out_of_range: usize,
+	// custom instruments without a sound event
+	pub custom: usize,
+}
+
+// Namespaces can only have a-z, 0-9, _, - and .
+pub fn clean_namespace(namespace: &str) -> String {
+	let cleaned: String = namespace.to_lowercase().chars().map(|c| if c.is_ascii_lowercase() || c.is_ascii_digit() || "_-.".contains(c) {c} else {'_'}).collect();
+	if cleaned.is_empty() {"song".to_string()} else {cleaned}
+}
+
+// Zip files with deflate, every entry gets the same date so the output is always the same
+struct ZipWriter {
+	data: Vec<u8>,
+	central_directory: Vec<u8>,
+	count: u16,
+}
+
+impl ZipWriter {
+	const DOS_TIME: u16 = 0;
+	const DOS_DATE: u16 = 1 << 5 | 1; // 1980-01-01
+
+	fn new() -> Self {
+		Self { data: Vec::new(), central_directory: Vec::new(), count: 0 }
+	}
+
+	fn add(&mut self, path: &str, content: &str) -> io::Result<()> {
+		let mut crc = Crc::new();
+		crc.update(content.as_bytes());
+		let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+		encoder.write_all(content.as_bytes())?;
+		let compressed = encoder.finish()?;
+		let offset = self.data.len() as u32;
+
+		// fields shared by the local header and the central directory
+		let mut common: Vec<u8> = Vec::new();
+		common.extend(20_u16.to_le_bytes()); // version needed
+		common.extend(0_u16.to_le_bytes()); // flags
+		common.extend(8_u16.to_le_bytes()); // deflate
+		common.extend(Self::DOS_TIME.to_le_bytes());
+		common.extend(Self::DOS_DATE.to_le_bytes());
+		common.extend(crc.sum().to_le_bytes());
+		common.extend((compressed.len() as u32).to_le_bytes());
+		common.extend((content.len() as u32).to_le_bytes());
+		common.extend((path.len() as u16).to_le_bytes());
+		common.extend(0_u16.to_le_bytes()); // extra field length
+
+		self.data.extend(0x04034b50_u32.to_le_bytes());
+		self.data.extend(&common);
+		self.data.extend(path.as_bytes());
+		self.data.extend(&compressed);
+
+		self.central_directory.extend(0x02014b50_u32.to_le_bytes());
+		self.central_directory.extend(20_u16.to_le_bytes()); // version made by
+		self.central_directory.extend(&common);
+		self.central_directory.extend(0_u16.to_le_bytes()); // comment length
+		self.central_directory.extend(0_u16.to_le_bytes()); // disk number
+		self.central_directory.extend(0_u16.to_le_bytes()); // internal attributes
+		self.central_directory.extend(0_u32.to_le_bytes()); // external attributes
+		self.central_directory.extend(offset.to_le_bytes());
+		self.central_directory.extend(path.as_bytes());
+
+		self.count += 1;
+		Ok(())
+	}
+
+	fn finish(self, writer: &mut impl Write) -> io::Result<()> {
+		writer.write_all(&self.data)?;
+		writer.write_all(&self.central_directory)?;
+		writer.write_all(&0x06054b50_u32.to_le_bytes())?;
+		writer.write_all(&0_u16.to_le_bytes())?; // disk number
+		writer.write_all(&0_u16.to_le_bytes())?; // disk with the central directory
+		writer.write_all(&self.count.to_le_bytes())?;
+		writer.write_all(&self.count.to_le_bytes())?;
+		writer.write_all(&(self.central_directory.len() as u32).to_le_bytes())?;
+		writer.write_all(&(self.data.len() as u32).to_le_bytes())?;
+		writer.write_all(&0_u16.to_le_bytes()) // comment length
+	}
+}
+
+// Functions that narrow the current tick down with a binary search, so every game tick only runs a few commands
+fn dispatch(files: &mut Vec<(String, String)>, folder: &str, namespace: &str, objective: &str, ticks: &[u32], node: &mut usize) -> String {
+	let name = format!("{namespace}:tree/{node}");
+	let path = format!("data/{namespace}/{folder}/tree/{node}.mcfunction");
+	*node += 1;
+	let mut content = String::new();
+	if ticks.len() <= 2 {
+		for tick in ticks {
+			content += &format!("execute if score $time {objective} matches {tick} run function {namespace}:notes/{tick}\n");
+		}
+	} else {
+		let (low, high) = ticks.split_at(ticks.len() / 2);
+		for half in [low, high] {
+			let child = dispatch(files, folder, namespace, objective, half, node);
+			content += &format!("execute if score $time {objective} matches {}..{} run function {child}\n", half[0], half[half.len() - 1]);
+		}
+	}
+	files.push((path, content));
+	name
+}
+
+pub fn write(writer: &mut impl Write, project: &Project, options: &DatapackOptions) -> io::Result<DatapackReport> {
+	let mut report = DatapackReport { out_of_range: 0, custom: 0 };
+	let namespace = clean_namespace(&options.namespace);
+	let objective = format!("{namespace}.tick");
+	let selector = if options.selector.trim().is_empty() {"@a"} else {options.selector.trim()};
+	let folder = if options.pack_format >= SINGULAR_FOLDERS_PACK_FORMAT {"function"} else {"functions"};
+
+	// game tick -> playsound commands
+	let mut ticks: Vec<(u32, String)> = Vec::new();
+	for layer in &project.layers {
+		let sound = match project.custom_instrument(layer.instrument) {
+			Some(custom) if custom.sound_event.trim().is_empty() => {
+				report.custom += layer.notes.len();
+				continue;
+			}
+			Some(custom) => custom.sound_event.trim().to_string(),
+			None => format!("minecraft:block.note_block.{}", MINECRAFT_INSTRUMENT_NAMES[layer.instrument as usize % MINECRAFT_INSTRUMENT_NAMES.len()]),
+		};
+		let base_note = project.base_note(layer.instrument);
+		for note in &layer.notes {
+			// playsound pitch goes from 0.5 to 2, two octaves like a note block
+			if !(base_note as f32 - 12.0..=base_note as f32 + 12.0).contains(&note.pitch()) {
+				report.out_of_range += 1;
+				continue;
+			}
+			let game_tick = (project.tempo.seconds_at(note.time as f64) * 20.0).round() as u32;
+			let pitch = render::note_speed(note.pitch(), base_note);
+			let volume = layer.note_gain(note);
+			// panned notes play from the side of the player, ^ is to the left
+			let pan = layer.note_pan(note);
+			let position = if pan == 0.0 {"~ ~ ~".to_string()} else {format!("^{:.2} ^ ^", -pan * 2.0)};
+			ticks.push((game_tick, format!("execute as {selector} at @s run playsound {sound} record @s {position} {volume} {pitch:.4}\n")));
+		}
+	}
+	ticks.sort();
+	let end = ticks.last().map(|(tick, _)| tick + 1).unwrap_or(0);
+
+	let mut files: Vec<(String, String)> = Vec::new();
+	let description = if project.name.is_empty() {"Note block song".to_string()} else {project.name.clone()};
+	files.push(("pack.mcmeta".to_string(), format!("{{\n\t\"pack\": {{\n\t\t\"pack_format\": {},\n\t\t\"description\": {}\n\t}}\n}}\n", options.pack_format, serde_json::to_string(&description)?)));
+	files.push((format!("data/minecraft/tags/{folder}/load.json"), format!("{{\n\t\"values\": [\"{namespace}:load\"]\n}}\n")));
+	files.push((format!("data/minecraft/tags/{folder}/tick.json"), format!("{{\n\t\"values\": [\"{namespace}:tick\"]\n}}\n")));
+	files.push((format!("data/{namespace}/{folder}/load.mcfunction"), format!("scoreboard objectives add {objective} dummy\n")));
+	files.push((format!("data/{namespace}/{folder}/play.mcfunction"), format!("scoreboard players set $time {objective} 0\nscoreboard players set $playing {objective} 1\n")));
+	files.push((format!("data/{namespace}/{folder}/stop.mcfunction"), format!("scoreboard players set $playing {objective} 0\nscoreboard players set $time {objective} 0\n")));
+	files.push((format!("data/{namespace}/{folder}/tick.mcfunction"), format!("execute if score $playing {objective} matches 1 run function {namespace}:step\n")));
+
+	let mut step = String::new();
+	let mut note_ticks: Vec<u32> = Vec::new();
+	let mut index = 0;
+	while index < ticks.len() {
+		let tick = ticks[index].0;
+		let mut content = String::new();
+		while index < ticks.len() && ticks[index].0 == tick {
+			content += &ticks[index].1;
+			index += 1;
+		}
+		files.push((format!("data/{namespace}/{folder}/notes/{tick}.mcfunction"), content));
+		note_ticks.push(tick);
+	}
+	if !note_ticks.is_empty() {
+		let root = dispatch(&mut files, folder, &namespace, &objective, &note_ticks, &mut 0);
+		step += &format!("function {root}\n");
+	}
+	step += &format!("scoreboard players add $time {objective} 1\n");
+	step += &format!("execute if score $time {objective} matches {end}.. run function {namespace}:stop\n");
+	files.push((format!("data/{namespace}/{folder}/step.mcfunction"), step));
+
+	files.sort();
+	let mut zip = ZipWriter::new();
+	for (path, content) in &files {
+		zip.add(path, content)?;
+	}
+	zip.finish(writer)?;
+	Ok(report)
+}
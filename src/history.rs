@@ -0,0 +1,60 @@
+use crate::project::{Marker, Note, Project, TempoChange};
+
+const MAX_UNDO_STEPS: usize = 200;
+// big songs get fewer steps, embedded sounds are shared between snapshots so they don't count
+const MAX_UNDO_BYTES: usize = 64 * 1024 * 1024;
+
+// roughly how much memory a snapshot takes up
+fn snapshot_size(project: &Project) -> usize {
+	let notes: usize = project.layers.iter().map(|layer| layer.notes.len()).sum();
+	notes * size_of::<Note>() + project.markers.len() * size_of::<Marker>() + project.tempo.changes.len() * size_of::<TempoChange>()
+		+ size_of::<Project>()
+}
+
+// Snapshot based undo history, every gesture pushes a copy of the project from before the change
+pub struct History {
+	undo_stack: Vec<Project>,
+	redo_stack: Vec<Project>,
+}
+
+impl History {
+	pub fn new() -> Self {
+		Self { undo_stack: Vec::new(), redo_stack: Vec::new() }
+	}
+
+	pub fn push(&mut self, before: Project) {
+		self.undo_stack.push(before);
+		let mut size: usize = self.undo_stack.iter().map(snapshot_size).sum();
+		// always keep the step that was just made
+		while self.undo_stack.len() > 1 && (self.undo_stack.len() > MAX_UNDO_STEPS || size > MAX_UNDO_BYTES) {
+			size -= snapshot_size(&self.undo_stack.remove(0));
+		}
+		self.redo_stack.clear();
+	}
+
+	// both give back the project that got replaced
+	pub fn undo(&mut self, project: &mut Project) -> Option<&Project> {
+		let before = self.undo_stack.pop()?;
+		self.redo_stack.push(std::mem::replace(project, before));
+		self.redo_stack.last()
+	}
+
+	pub fn redo(&mut self, project: &mut Project) -> Option<&Project> {
+		let after = self.redo_stack.pop()?;
+		self.undo_stack.push(std::mem::replace(project, after));
+		self.undo_stack.last()
+	}
+
+	pub fn can_undo(&self) -> bool {
+		!self.undo_stack.is_empty()
+	}
+
+	pub fn can_redo(&self) -> bool {
+		!self.redo_stack.is_empty()
+	}
+
+	pub fn clear(&mut self) {
+		self.undo_stack.clear();
+		self.redo_stack.clear();
+	}
+}
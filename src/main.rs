@@ -1,7 +1,26 @@
 mod app;
+mod clipboard;
+mod datapack;
+mod history;
+mod midi;
+mod nbs;
 mod project;
+mod render;
+mod schematic;
+mod sequencer;
+mod sounds;
 
 fn main() {
+	// load the sounds before opening the window so a broken sound file gives a proper error
+	let noteblock_sounds = match sounds::load_sounds() {
+		Ok(sounds) => sounds,
+		Err(error) => {
+			rfd::MessageDialog::new().set_level(rfd::MessageLevel::Error).set_title("Note Block Music").set_description(&error).show();
+			eprintln!("{error}");
+			return;
+		}
+	};
+
     let iconbytes = include_bytes!("noteblock.bin");
     let icon = eframe::egui::IconData {
         width: 16,
@@ -15,5 +34,5 @@ fn main() {
         },
         ..Default::default()
     };
-	eframe::run_native("Note Block Music", options, Box::new(|cc| Ok(Box::new(app::App::new(cc))))).expect("Failed to run App!");
+	eframe::run_native("Note Block Music", options, Box::new(|cc| Ok(Box::new(app::App::new(cc, noteblock_sounds))))).expect("Failed to run App!");
 }
\ No newline at end of file
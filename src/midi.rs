@@ -0,0 +1,560 @@
+// Standard MIDI File import and export
+use std::{collections::{HashMap, HashSet}, io::{self, Read, Write}};
+
+use crate::{app::SOUND_FILE_NAMES, project::{self, Layer, Note, Project, TempoTrack}};
+
+const PERCUSSION_CHANNEL: u8 = 9;
+// drums don't have a pitch in minecraft so they all go in the middle of the range
+const PERCUSSION_NOTE: u8 = 66;
+// one project tick is a 16th note when exporting, unless the song is too slow for that
+const TICKS_PER_QUARTER: u16 = 480;
+// the tempo is 3 bytes of microseconds per quarter note
+const MAX_TEMPO: f32 = 0xFF_FFFF as f32;
+
+fn invalid(message: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Picks the closest of the 16 note block instruments for a General MIDI program
+pub fn instrument_for_program(program: u8) -> u8 {
+	match program {
+		4 | 5 => 15, // electric piano -> pling
+		0..=7 => 0, // piano -> harp
+		8 | 9 | 14 | 112 => 7, // celesta, glockenspiel, tubular bells, tinkle bell -> bell
+		10 | 108 => 8, // music box, kalimba -> icechime
+		11 | 114 => 10, // vibraphone, steel drums -> iron xylophone
+		12 | 13 => 9, // marimba, xylophone -> xylobone
+		15 | 104..=106 => 14, // dulcimer, sitar, banjo, shamisen -> banjo
+		16..=23 => 6, // organ, accordion, harmonica -> flute
+		24..=31 => 5, // guitar
+		32..=39 => 1, // bass -> double bass
+		47 | 116..=118 => 2, // timpani, taiko, toms, synth drum -> bass drum
+		40..=55 => 0, // strings and ensemble -> harp
+		58 | 109 => 12, // tuba, bagpipe -> didgeridoo
+		56..=63 => 13, // brass -> bit
+		64..=79 | 110 | 111 => 6, // reed, pipe, fiddle, shanai -> flute
+		80..=87 => 13, // synth lead -> bit
+		88..=103 => 15, // synth pad and effects -> pling
+		113 => 11, // agogo -> cow bell
+		115 | 120..=127 => 4, // woodblock and sound effects -> click
+		119 => 3, // reverse cymbal -> snare drum
+		_ => 0,
+	}
+}
+
+// General MIDI program for every note block instrument, None for the drums
+fn program_for_instrument(instrument: u8) -> Option<u8> {
+	match instrument {
+		1 => Some(32), // double bass -> acoustic bass
+		2..=4 => None,
+		5 => Some(24), // guitar -> nylon guitar
+		6 => Some(73), // flute
+		7 => Some(9), // bell -> glockenspiel
+		8 => Some(14), // icechime -> tubular bells
+		9 => Some(13), // xylobone -> xylophone
+		10 => Some(11), // iron xylophone -> vibraphone
+		11 => Some(113), // cow bell -> agogo
+		12 => Some(58), // didgeridoo -> tuba
+		13 => Some(80), // bit -> square lead
+		14 => Some(105), // banjo
+		15 => Some(4), // pling -> electric piano
+		_ => Some(0), // harp -> piano
+	}
+}
+
+// Drum kit key for the percussion instruments
+fn percussion_key(instrument: u8) -> u8 {
+	match instrument {
+		2 => 36, // bass drum
+		3 => 38, // snare drum
+		_ => 42, // closed hi-hat
+	}
+}
+
+// Channel 10 drum kit sounds -> bass drum, snare drum or click
+fn instrument_for_percussion(key: u8) -> u8 {
+	match key {
+		35 | 36 | 41 | 43 | 45 | 47 | 48 | 50 => 2, // kicks and toms
+		37..=40 => 3, // snares and claps
+		_ => 4, // hats, cymbals and everything else
+	}
+}
+
+fn read_u16(data: &[u8], pos: usize) -> io::Result<u16> {
+	data.get(pos..pos + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]])).ok_or_else(|| invalid("Unexpected end of file"))
+}
+
+fn read_u32(data: &[u8], pos: usize) -> io::Result<u32> {
+	data.get(pos..pos + 4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).ok_or_else(|| invalid("Unexpected end of file"))
+}
+
+fn read_byte(data: &[u8], pos: &mut usize) -> io::Result<u8> {
+	let byte = *data.get(*pos).ok_or_else(|| invalid("Unexpected end of track"))?;
+	*pos += 1;
+	Ok(byte)
+}
+
+fn read_variable(data: &[u8], pos: &mut usize) -> io::Result<u32> {
+	let mut value: u32 = 0;
+	for _ in 0..4 {
+		let byte = read_byte(data, pos)?;
+		value = (value << 7) | (byte & 0x7F) as u32;
+		if byte & 0x80 == 0 {
+			return Ok(value);
+		}
+	}
+	Err(invalid("Variable length number is too long"))
+}
+
+// A layer to be, notes are in seconds so they can be quantized to any TPS
+pub struct MidiTrack {
+	pub name: String,
+	pub instrument: u8,
+	// (seconds, key, volume)
+	pub notes: Vec<(f64, u8, u8)>,
+	// (track, channel, instrument), percussion needs one layer per drum instrument
+	key: (usize, u8, u8),
+}
+
+struct RawNote {
+	tick: u64,
+	track: usize,
+	channel: u8,
+	key: u8,
+	velocity: u8,
+	program: u8,
+}
+
+pub fn read(reader: &mut impl Read) -> io::Result<Vec<MidiTrack>> {
+	let mut data = Vec::new();
+	reader.read_to_end(&mut data)?;
+
+	if data.get(0..4) != Some(b"MThd") {
+		return Err(invalid("Not a MIDI file"));
+	}
+	let header_length = read_u32(&data, 4)? as usize;
+	let format = read_u16(&data, 8)?;
+	let track_count = read_u16(&data, 10)? as usize;
+	let division = read_u16(&data, 12)?;
+	if format > 1 {
+		return Err(invalid("Only type 0 and type 1 MIDI files are supported"));
+	}
+
+	let mut notes: Vec<RawNote> = Vec::new();
+	let mut tempos: Vec<(u64, u32)> = Vec::new(); // (tick, microseconds per quarter note)
+	let mut track_names: Vec<String> = Vec::new();
+
+	let mut pos = 8 + header_length;
+	for track in 0..track_count {
+		let length = read_u32(&data, pos + 4)? as usize;
+		if data.get(pos..pos + 4) != Some(b"MTrk") {
+			return Err(invalid("Missing track chunk"));
+		}
+		let end = (pos + 8 + length).min(data.len());
+		let chunk = &data[pos + 8..end];
+		pos = end;
+
+		let mut cursor = 0;
+		let mut tick: u64 = 0;
+		let mut running_status: u8 = 0;
+		let mut programs = [0_u8; 16];
+		let mut track_name = String::new();
+		while cursor < chunk.len() {
+			tick += read_variable(chunk, &mut cursor)? as u64;
+			let mut status = read_byte(chunk, &mut cursor)?;
+			if status < 0x80 {
+				// running status, this byte is already data
+				status = running_status;
+				cursor -= 1;
+			}
+			match status {
+				0xFF => {
+					let kind = read_byte(chunk, &mut cursor)?;
+					let length = read_variable(chunk, &mut cursor)? as usize;
+					let meta = chunk.get(cursor..cursor + length).ok_or_else(|| invalid("Unexpected end of track"))?;
+					cursor += length;
+					match kind {
+						0x03 if track_name.is_empty() => track_name = String::from_utf8_lossy(meta).trim().to_string(),
+						0x51 if length == 3 => tempos.push((tick, u32::from_be_bytes([0, meta[0], meta[1], meta[2]]))),
+						0x2F => break,
+						_ => {}
+					}
+				}
+				0xF0 | 0xF7 => {
+					let length = read_variable(chunk, &mut cursor)? as usize;
+					cursor += length;
+				}
+				0x80..=0xEF => {
+					running_status = status;
+					let channel = status & 0x0F;
+					match status & 0xF0 {
+						0x90 => {
+							let key = read_byte(chunk, &mut cursor)?;
+							let velocity = read_byte(chunk, &mut cursor)?;
+							if velocity > 0 {
+								notes.push(RawNote { tick, track, channel, key, velocity, program: programs[channel as usize] });
+							}
+						}
+						0xC0 => programs[channel as usize] = read_byte(chunk, &mut cursor)?,
+						0xD0 => cursor += 1,
+						_ => cursor += 2,
+					}
+				}
+				_ => return Err(invalid("Unknown MIDI event")),
+			}
+		}
+		track_names.push(track_name);
+	}
+
+	// ticks to seconds
+	tempos.sort_by_key(|tempo| tempo.0);
+	let seconds = |tick: u64| -> f64 {
+		if division & 0x8000 != 0 {
+			// smpte time, frames per second and ticks per frame
+			let fps = match (division >> 8) as u8 as i8 {
+				-29 => 29.97,
+				fps => -(fps as f64),
+			};
+			return tick as f64 / (fps * (division & 0xFF) as f64);
+		}
+		let ticks_per_quarter = division.max(1) as f64;
+		let mut time = 0.0;
+		let mut last_tick = 0;
+		let mut tempo = 500000; // 120 bpm
+		for &(change_tick, change_tempo) in &tempos {
+			if change_tick >= tick {
+				break;
+			}
+			time += (change_tick - last_tick) as f64 / ticks_per_quarter * tempo as f64 / 1000000.0;
+			last_tick = change_tick;
+			tempo = change_tempo;
+		}
+		time + (tick - last_tick) as f64 / ticks_per_quarter * tempo as f64 / 1000000.0
+	};
+
+	let mut tracks: Vec<MidiTrack> = Vec::new();
+	for note in &notes {
+		let percussion = note.channel == PERCUSSION_CHANNEL;
+		let instrument = if percussion {instrument_for_percussion(note.key)} else {instrument_for_program(note.program)};
+		let key = (note.track, note.channel, if percussion {instrument} else {0});
+		let index = match tracks.iter().position(|track| track.key == key) {
+			Some(index) => index,
+			None => {
+				let track_name = if track_names[note.track].is_empty() {format!("Track {}", note.track + 1)} else {track_names[note.track].clone()};
+				let name = if percussion {
+					format!("{track_name} (drums {})", SOUND_FILE_NAMES[instrument as usize])
+				} else {
+					format!("{track_name} (ch {})", note.channel + 1)
+				};
+				tracks.push(MidiTrack { name, instrument, notes: Vec::new(), key });
+				tracks.len() - 1
+			}
+		};
+		let volume = (note.velocity.min(127) as f32 * 100.0 / 127.0).round() as u8;
+		tracks[index].notes.push((seconds(note.tick), if percussion {PERCUSSION_NOTE} else {note.key}, volume));
+	}
+	tracks.sort_by_key(|track| track.key);
+
+	Ok(tracks)
+}
+
+// Preview state for the import dialog
+pub struct MidiImport {
+	pub tracks: Vec<MidiTrack>,
+	pub transpose: Vec<i32>,
+	pub tps: f32,
+}
+
+impl MidiImport {
+	pub fn new(tracks: Vec<MidiTrack>, tps: f32) -> Self {
+		let transpose = vec![0; tracks.len()];
+		Self { tracks, transpose, tps }
+	}
+
+	fn transposed(note: u8, transpose: i32) -> u8 {
+		(note as i32 + transpose).clamp(0, 127) as u8
+	}
+
+	pub fn out_of_range(&self, track: usize) -> usize {
+		self.tracks[track].notes.iter().filter(|(_, note, _)| !(54..=78).contains(&Self::transposed(*note, self.transpose[track]))).count()
+	}
+
+	// Whole octave shift that puts the most notes in the note block range
+	pub fn auto_transpose(&mut self, track: usize) {
+		let mut best = (usize::MAX, 0);
+		for octave in [0, -1, 1, -2, 2, -3, 3, -4, 4] {
+			self.transpose[track] = octave * 12;
+			let out_of_range = self.out_of_range(track);
+			if out_of_range < best.0 {
+				best = (out_of_range, octave * 12);
+			}
+		}
+		self.transpose[track] = best.1;
+	}
+
+	pub fn to_project(&self) -> Project {
+		let mut project = Project::new();
+		project.layers.clear();
+		for (index, track) in self.tracks.iter().enumerate() {
+			let mut layer = Layer::new(track.name.clone(), track.instrument);
+			layer.color = project::layer_color(index);
+			let mut placed: HashSet<(u32, u8)> = HashSet::new();
+			for &(seconds, note, volume) in &track.notes {
+				let mut note = Note::new((seconds * self.tps as f64).round() as u32, Self::transposed(note, self.transpose[index]));
+				note.volume = volume;
+				// quantizing can put two notes on the same spot
+				if placed.insert((note.time, note.note)) {
+					layer.notes.push(note);
+				}
+			}
+			project.layers.push(layer);
+		}
+		if project.layers.is_empty() {
+			project.layers.push(Layer::new("Layer 1".to_string(), 0));
+		}
+		project.tempo = TempoTrack::new(self.tps);
+		project.sort_notes();
+		project.assign_note_ids();
+		project
+	}
+}
+
+pub struct MidiExportOptions {
+	// in project ticks, note blocks don't have a length
+	pub note_length: f32,
+}
+
+pub struct MidiExportReport {
+	// midi notes have no fine pitch, these play the nearest key instead
+	pub cents: usize,
+}
+
+impl MidiExportOptions {
+	pub fn new() -> Self {
+		Self { note_length: 1.0 }
+	}
+}
+
+fn write_variable(buf: &mut Vec<u8>, value: u32) {
+	let mut bytes = vec![(value & 0x7F) as u8];
+	let mut value = value >> 7;
+	while value > 0 {
+		bytes.push((value & 0x7F) as u8 | 0x80);
+		value >>= 7;
+	}
+	bytes.reverse();
+	buf.extend(bytes);
+}
+
+fn write_meta(buf: &mut Vec<u8>, kind: u8, data: &[u8]) {
+	write_meta_after(buf, 0, kind, data);
+}
+
+// delta is the midi ticks since the last event in the track
+fn write_meta_after(buf: &mut Vec<u8>, delta: u32, kind: u8, data: &[u8]) {
+	write_variable(buf, delta);
+	buf.extend([0xFF, kind]);
+	write_variable(buf, data.len() as u32);
+	buf.extend(data);
+}
+
+fn write_chunk(writer: &mut impl Write, track: &[u8]) -> io::Result<()> {
+	writer.write_all(b"MTrk")?;
+	writer.write_all(&(track.len() as u32 + 4).to_be_bytes())?;
+	writer.write_all(track)?;
+	writer.write_all(&[0, 0xFF, 0x2F, 0]) // end of track
+}
+
+// Writes a type 1 file with a tempo track and one track per layer
+pub fn write(writer: &mut impl Write, project: &Project, options: &MidiExportOptions) -> io::Result<MidiExportReport> {
+	let mut report = MidiExportReport { cents: 0 };
+	writer.write_all(b"MThd")?;
+	writer.write_all(&6_u32.to_be_bytes())?;
+	writer.write_all(&1_u16.to_be_bytes())?;
+	writer.write_all(&(project.layers.len() as u16 + 1).to_be_bytes())?;
+	writer.write_all(&TICKS_PER_QUARTER.to_be_bytes())?;
+
+	// project ticks per quarter note, 4 unless a quarter note of the slowest tempo doesn't fit in the tempo field,
+	// 1 is enough for anything down to MIN_TPS
+	let slowest = project.tempo.all().fold(f32::INFINITY, f32::min);
+	let mut quarter = 4;
+	while quarter > 1 && 1_000_000.0 * quarter as f32 / slowest > MAX_TEMPO {
+		quarter /= 2;
+	}
+	let ticks_per_tick = TICKS_PER_QUARTER as u32 / quarter;
+	let microseconds_per_quarter = |tps: f32| (1_000_000.0 * quarter as f32 / tps).min(MAX_TEMPO) as u32;
+
+	// tempo track
+	let mut track: Vec<u8> = Vec::new();
+	write_meta(&mut track, 0x03, project.name.as_bytes());
+	write_meta(&mut track, 0x51, &microseconds_per_quarter(project.tempo.tps).to_be_bytes()[1..]);
+	// the denominator is a power of 2, so beat lengths that aren't count project ticks instead
+	let signature = project.time_signature;
+	let ticks_per_beat = signature.ticks_per_beat();
+	let ticks_per_whole = quarter * 4;
+	let (numerator, denominator) = if ticks_per_beat.is_power_of_two() && ticks_per_beat <= ticks_per_whole {
+		(signature.beats_per_bar.max(1), (ticks_per_whole / ticks_per_beat).trailing_zeros() as u8)
+	} else {
+		(signature.ticks_per_bar().min(255) as u8, ticks_per_whole.trailing_zeros() as u8)
+	};
+	write_meta(&mut track, 0x58, &[numerator, denominator, 24, 8]);
+	let mut last_time = 0;
+	for change in &project.tempo.changes {
+		let time = change.tick.saturating_mul(ticks_per_tick);
+		write_meta_after(&mut track, time - last_time, 0x51, &microseconds_per_quarter(change.tps).to_be_bytes()[1..]);
+		last_time = time;
+	}
+	write_chunk(writer, &track)?;
+
+	// every melodic instrument gets its own channel so program changes don't clash
+	let mut channels: Vec<u8> = Vec::new();
+	let layer_channels: Vec<(Option<u8>, u8)> = project.layers.iter().map(|layer| {
+		let program = program_for_instrument(layer.instrument);
+		let channel = match program {
+			Some(_) => {
+				let index = match channels.iter().position(|instrument| *instrument == layer.instrument) {
+					Some(index) => index,
+					None => {
+						channels.push(layer.instrument);
+						channels.len() - 1
+					}
+				};
+				let channel = (index % 15) as u8;
+				if channel >= PERCUSSION_CHANNEL {channel + 1} else {channel}
+			}
+			None => PERCUSSION_CHANNEL,
+		};
+		(program, channel)
+	}).collect();
+	let key_for = |layer: &Layer, note: &Note, program: Option<u8>| match program {
+		Some(_) => note.pitch().round().clamp(0.0, 127.0) as u8,
+		None => percussion_key(layer.instrument),
+	};
+	// when every note starts on each channel and key, a note has to end before the next one on the same key
+	// starts or its note off would cut that one short
+	let mut starts: HashMap<(u8, u8), Vec<u32>> = HashMap::new();
+	for (layer, &(program, channel)) in project.layers.iter().zip(&layer_channels) {
+		for note in &layer.notes {
+			starts.entry((channel, key_for(layer, note, program))).or_default().push(note.time.saturating_mul(ticks_per_tick));
+		}
+	}
+	for times in starts.values_mut() {
+		times.sort();
+	}
+
+	let length = (options.note_length * ticks_per_tick as f32).max(1.0) as u32;
+	for (layer, &(program, channel)) in project.layers.iter().zip(&layer_channels) {
+		let mut track: Vec<u8> = Vec::new();
+		write_meta(&mut track, 0x03, layer.name.as_bytes());
+		if let Some(program) = program {
+			write_variable(&mut track, 0);
+			track.extend([0xC0 | channel, program]);
+		}
+
+		// (time, is note on, key, velocity), note offs go first when they line up with a note on
+		let mut events: Vec<(u32, bool, u8, u8)> = Vec::new();
+		for note in &layer.notes {
+			if program.is_some() && note.cents != 0 {
+				report.cents += 1;
+			}
+			let key = key_for(layer, note, program);
+			let start = note.time.saturating_mul(ticks_per_tick);
+			let velocity = ((layer.note_gain(note) * 127.0).round() as u8).max(1);
+			let times = &starts[&(channel, key)];
+			let end = match times.get(times.partition_point(|&time| time <= start)) {
+				Some(&next) => (start + length).min(next),
+				None => start + length,
+			};
+			events.push((start, true, key, velocity));
+			events.push((end, false, key, 0));
+		}
+		events.sort();
+		let mut last_time = 0;
+		for (time, on, key, velocity) in events {
+			write_variable(&mut track, time - last_time);
+			last_time = time;
+			if on {
+				track.extend([0x90 | channel, key, velocity]);
+			} else {
+				track.extend([0x80 | channel, key, 0]);
+			}
+		}
+		write_chunk(writer, &track)?;
+	}
+	Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// what a player would see in the exported file
+	fn export(project: &Project, options: &MidiExportOptions) -> Vec<MidiTrack> {
+		let mut data: Vec<u8> = Vec::new();
+		write(&mut data, project, options).unwrap();
+		read(&mut data.as_slice()).unwrap()
+	}
+
+	#[test]
+	fn round_trip() {
+		let mut project = Project::new();
+		project.tempo = TempoTrack::new(8.0);
+		project.layers[0] = Layer::new("Flute".to_string(), 6);
+		for (time, key) in [(0, 60), (4, 64), (12, 67)] {
+			project.add_note(0, Note::new(time, key));
+		}
+
+		let tracks = export(&project, &MidiExportOptions::new());
+		assert_eq!(tracks.len(), 1);
+		assert_eq!(tracks[0].instrument, 6);
+		let imported = MidiImport::new(tracks, 8.0).to_project();
+		let notes: Vec<(u32, u8)> = imported.layers[0].notes.iter().map(|note| (note.time, note.note)).collect();
+		assert_eq!(notes, vec![(0, 60), (4, 64), (12, 67)]);
+	}
+
+	#[test]
+	fn repeated_notes_end_early() {
+		let mut project = Project::new();
+		for time in [0, 1] {
+			project.add_note(0, Note::new(time, 60));
+		}
+		let mut data: Vec<u8> = Vec::new();
+		write(&mut data, &project, &MidiExportOptions { note_length: 4.0 }).unwrap();
+
+		// the first note ends where the second starts, 120 midi ticks in, and the second one gets its full 480
+		let events = [0x78, 0x80, 60, 0, 0, 0x90, 60, 127, 0x83, 0x60, 0x80, 60, 0];
+		assert!(data.windows(events.len()).any(|window| window == events));
+	}
+
+	#[test]
+	fn tempo_changes() {
+		let mut project = Project::new();
+		project.tempo = TempoTrack::new(10.0);
+		project.tempo.set(8, 20.0);
+		for time in [0, 8, 16] {
+			project.add_note(0, Note::new(time, 60));
+		}
+
+		let tracks = export(&project, &MidiExportOptions::new());
+		for (&(seconds, _, _), time) in tracks[0].notes.iter().zip([0, 8, 16]) {
+			assert!((seconds - project.tempo.seconds_at(time as f64)).abs() < 0.001, "{seconds} at tick {time}");
+		}
+	}
+
+	#[test]
+	fn slowest_tempo() {
+		let mut project = Project::new();
+		// too slow for a 16th note per tick, the whole export uses a bigger note per tick instead
+		project.tempo = TempoTrack::new(10.0);
+		project.tempo.set(4, 0.1);
+		for time in [0, 4, 8] {
+			project.add_note(0, Note::new(time, 60));
+		}
+
+		let tracks = export(&project, &MidiExportOptions::new());
+		let seconds: Vec<f64> = tracks[0].notes.iter().map(|note| note.0).collect();
+		for (seconds, expected) in seconds.iter().zip([0.0, 0.4, 40.4]) {
+			assert!((seconds - expected).abs() < 0.001, "{seconds} instead of {expected}");
+		}
+	}
+}
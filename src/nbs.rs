@@ -0,0 +1,546 @@
+// Open Note Block Studio (.nbs) import and export, see https://opennbs.org/nbs
+use std::io::{self, Read, Write};
+
+use crate::{app::SOUND_FILE_NAMES, project::{self, CustomInstrument, FIRST_CUSTOM_INSTRUMENT, Layer, MAX_CUSTOM_INSTRUMENTS, MIN_TPS, Note, Project, TempoTrack, TimeSignature}};
+
+// nbs key 33 is F#3, which is midi note 54
+const KEY_OFFSET: u8 = 21;
+const MAX_KEY: u8 = 87;
+const NBS_VERSION: u8 = 5;
+
+fn invalid(message: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// for songs that nbs can't store
+fn too_big(message: String) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+	let mut buf = [0; 1];
+	reader.read_exact(&mut buf)?;
+	Ok(buf[0])
+}
+
+fn read_i16(reader: &mut impl Read) -> io::Result<i16> {
+	let mut buf = [0; 2];
+	reader.read_exact(&mut buf)?;
+	Ok(i16::from_le_bytes(buf))
+}
+
+fn read_i32(reader: &mut impl Read) -> io::Result<i32> {
+	let mut buf = [0; 4];
+	reader.read_exact(&mut buf)?;
+	Ok(i32::from_le_bytes(buf))
+}
+
+fn read_string(reader: &mut impl Read) -> io::Result<String> {
+	let len = read_i32(reader)?;
+	if len < 0 {
+		return Err(invalid("Negative string length"));
+	}
+	let mut buf = Vec::new();
+	reader.take(len as u64).read_to_end(&mut buf)?;
+	if buf.len() != len as usize {
+		return Err(io::ErrorKind::UnexpectedEof.into());
+	}
+	Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+struct NbsNote {
+	tick: u32,
+	layer: usize,
+	instrument: u8,
+	key: u8,
+	velocity: u8,
+	// 0 - 200, 100 is center
+	panning: u8,
+	// in cents
+	pitch: i16,
+}
+
+pub fn read(reader: &mut impl Read) -> io::Result<Project> {
+	// header
+	let first = read_i16(reader)?;
+	let (version, vanilla_count) = if first == 0 {
+		let version = read_u8(reader)?;
+		if version > 5 {
+			return Err(invalid("Unsupported NBS version"));
+		}
+		let vanilla_count = read_u8(reader)?;
+		if version >= 3 {
+			read_i16(reader)?; // song length
+		}
+		(version, vanilla_count)
+	} else {
+		(0, 10) // classic files start with the song length, which is never 0
+	};
+	let layer_count = read_i16(reader)?.max(0) as usize;
+	let name = read_string(reader)?;
+	let author = read_string(reader)?;
+	read_string(reader)?; // original author
+	let description = read_string(reader)?;
+	let tempo = read_i16(reader)? as f32 / 100.0;
+	if tempo < MIN_TPS {
+		return Err(invalid(&format!("The song has a tempo of {tempo} ticks per second")));
+	}
+	read_u8(reader)?; // auto-saving
+	read_u8(reader)?; // auto-saving duration
+	let beats_per_bar = read_u8(reader)?; // time signature, nbs beats are always 4 ticks
+	for _ in 0..5 {
+		read_i32(reader)?; // minutes spent, left clicks, right clicks, blocks added, blocks removed
+	}
+	read_string(reader)?; // imported file name
+	if version >= 4 {
+		read_u8(reader)?; // loop on/off
+		read_u8(reader)?; // max loop count
+		read_i16(reader)?; // loop start tick
+	}
+
+	// notes
+	let mut notes: Vec<NbsNote> = Vec::new();
+	let mut tick: i64 = -1;
+	loop {
+		let jump = read_i16(reader)? as u16;
+		if jump == 0 {
+			break;
+		}
+		tick += jump as i64;
+		let mut layer: i64 = -1;
+		loop {
+			let jump = read_i16(reader)? as u16;
+			if jump == 0 {
+				break;
+			}
+			layer += jump as i64;
+			let instrument = read_u8(reader)?;
+			let key = read_u8(reader)?;
+			let mut velocity = 100;
+			let mut panning = 100;
+			let mut pitch = 0;
+			if version >= 4 {
+				velocity = read_u8(reader)?.min(100);
+				panning = read_u8(reader)?.min(200);
+				pitch = read_i16(reader)?;
+			}
+			notes.push(NbsNote { tick: tick as u32, layer: layer as usize, instrument, key, velocity, panning, pitch });
+		}
+	}
+
+	// layers, some old files end right after the notes so missing layer info is fine
+	// the settings go on every layer that the nbs layer gets split into
+	let mut layer_settings: Vec<Layer> = Vec::new();
+	for _ in 0..layer_count {
+		let Ok(name) = read_string(reader) else { break };
+		let mut settings = Layer::new(name, 0);
+		if version >= 4 {
+			settings.locked = read_u8(reader)? == 1;
+		}
+		settings.volume = read_u8(reader)?.min(100);
+		if version >= 2 {
+			settings.pan = (read_u8(reader)?.min(200) as i16 - 100) as i8; // stereo, 100 is center
+		}
+		layer_settings.push(settings);
+	}
+
+	// custom instruments come after the vanilla ones in the nbs instrument numbers
+	let mut custom_instruments: Vec<CustomInstrument> = Vec::new();
+	if let Ok(count) = read_u8(reader) {
+		for _ in 0..count {
+			let name = read_string(reader)?;
+			let path = read_string(reader)?;
+			let key = read_u8(reader)?;
+			read_u8(reader)?; // press piano key
+			let mut custom = CustomInstrument::new(name, path);
+			custom.base_note = key.min(MAX_KEY) + KEY_OFFSET;
+			// the rest still have to be read past, notes using them become harp
+			if custom_instruments.len() < MAX_CUSTOM_INSTRUMENTS {
+				custom_instruments.push(custom);
+			}
+		}
+	}
+	let instrument_for = |instrument: u8| {
+		if instrument < vanilla_count {
+			// newer vanilla instruments than this project knows about become harp
+			if (instrument as usize) < SOUND_FILE_NAMES.len() {instrument} else {0}
+		} else if ((instrument - vanilla_count) as usize) < custom_instruments.len() {
+			FIRST_CUSTOM_INSTRUMENT + instrument - vanilla_count
+		} else {
+			0
+		}
+	};
+	let instrument_name = |instrument: u8| match instrument.checked_sub(FIRST_CUSTOM_INSTRUMENT) {
+		Some(index) => custom_instruments[index as usize].name.clone(),
+		None => SOUND_FILE_NAMES[instrument as usize].to_string(),
+	};
+
+	// nbs instruments are per note, so split every nbs layer into one layer per instrument
+	let nbs_layer_count = notes.iter().map(|note| note.layer + 1).max().unwrap_or(0).max(layer_count).max(1);
+	let mut layers: Vec<Layer> = Vec::new();
+	for nbs_layer in 0..nbs_layer_count {
+		let settings = layer_settings.get(nbs_layer);
+		let name = match settings {
+			Some(settings) if !settings.name.is_empty() => settings.name.clone(),
+			_ => format!("Layer {}", nbs_layer + 1),
+		};
+		let mut split: Vec<Layer> = Vec::new();
+		for note in notes.iter().filter(|note| note.layer == nbs_layer) {
+			let instrument = instrument_for(note.instrument);
+			let index = match split.iter().position(|layer| layer.instrument == instrument) {
+				Some(index) => index,
+				None => {
+					let layer_name = if split.is_empty() {name.clone()} else {format!("{name} ({})", instrument_name(instrument))};
+					split.push(Layer::new(layer_name, instrument));
+					split.len() - 1
+				}
+			};
+			let mut new_note = Note::new(note.tick, note.key.saturating_add(KEY_OFFSET).min(127));
+			new_note.volume = note.velocity;
+			new_note.pan = (note.panning as i16 - 100) as i8;
+			new_note.cents = note.pitch;
+			split[index].notes.push(new_note);
+		}
+		if split.is_empty() {
+			split.push(Layer::new(name, 0));
+		}
+		if let Some(settings) = settings {
+			for layer in &mut split {
+				layer.pan = settings.pan;
+				layer.volume = settings.volume;
+				layer.locked = settings.locked;
+			}
+		}
+		layers.extend(split);
+	}
+
+	for (index, layer) in layers.iter_mut().enumerate() {
+		layer.color = project::layer_color(index);
+	}
+
+	let mut project = Project::new();
+	project.layers = layers;
+	project.name = name;
+	project.author = author;
+	project.description = description;
+	project.custom_instruments = custom_instruments;
+	project.tempo = TempoTrack::new(tempo);
+	project.time_signature = TimeSignature { beats_per_bar: beats_per_bar.max(1), ticks_per_beat: 4 };
+	project.sort_notes();
+	project.assign_note_ids();
+	Ok(project)
+}
+
+fn write_u8(writer: &mut impl Write, value: u8) -> io::Result<()> {
+	writer.write_all(&[value])
+}
+
+fn write_i16(writer: &mut impl Write, value: i16) -> io::Result<()> {
+	writer.write_all(&value.to_le_bytes())
+}
+
+fn write_i32(writer: &mut impl Write, value: i32) -> io::Result<()> {
+	writer.write_all(&value.to_le_bytes())
+}
+
+fn write_string(writer: &mut impl Write, value: &str) -> io::Result<()> {
+	write_i32(writer, value.len() as i32)?;
+	writer.write_all(value.as_bytes())
+}
+
+pub struct WriteReport {
+	// still written, but minecraft can't play them
+	pub outside_noteblock_range: usize,
+	// nbs has no key for these so they're left out
+	pub dropped: usize,
+	// nbs has one tempo for the whole song, the starting one gets used
+	pub tempo_changes: usize,
+}
+
+// Writes a version 5 nbs file
+pub fn write(writer: &mut impl Write, project: &Project) -> io::Result<WriteReport> {
+	let mut report = WriteReport { outside_noteblock_range: 0, dropped: 0, tempo_changes: project.tempo.changes.len() };
+
+	// nbs only allows one note per layer per tick, so chords get spread over extra layers
+	let mut nbs_layers: Vec<(String, &Layer)> = Vec::new();
+	let mut nbs_notes: Vec<NbsNote> = Vec::new();
+	for layer in &project.layers {
+		let mut notes: Vec<&Note> = layer.notes.iter().collect();
+		notes.sort_by_key(|note| (note.time, note.note));
+		let first_layer = nbs_layers.len();
+		let mut stack = 0;
+		for (index, note) in notes.iter().enumerate() {
+			stack = if index > 0 && notes[index - 1].time == note.time {stack + 1} else {0};
+			if note.note < KEY_OFFSET || note.note > KEY_OFFSET + MAX_KEY {
+				report.dropped += 1;
+				continue;
+			}
+			if note.note < 54 || note.note > 78 {
+				report.outside_noteblock_range += 1;
+			}
+			while nbs_layers.len() <= first_layer + stack {
+				let name = if nbs_layers.len() == first_layer {layer.name.clone()} else {format!("{} ({})", layer.name, nbs_layers.len() - first_layer + 1)};
+				nbs_layers.push((name, layer));
+			}
+			nbs_notes.push(NbsNote { tick: note.time, layer: first_layer + stack, instrument: layer.instrument, key: note.note - KEY_OFFSET, velocity: note.volume.min(100), panning: (note.pan.clamp(-100, 100) as i16 + 100) as u8, pitch: note.cents });
+		}
+		if nbs_layers.len() == first_layer {
+			nbs_layers.push((layer.name.clone(), layer));
+		}
+	}
+	nbs_notes.sort_by_key(|note| (note.tick, note.layer));
+	let song_length = nbs_notes.last().map(|note| note.tick).unwrap_or(0);
+
+	if nbs_layers.len() > i16::MAX as usize {
+		return Err(too_big(format!("The song would need {} layers, Note Block Studio files can only have {}", nbs_layers.len(), i16::MAX)));
+	}
+
+	// header
+	write_i16(writer, 0)?;
+	write_u8(writer, NBS_VERSION)?;
+	write_u8(writer, SOUND_FILE_NAMES.len() as u8)?;
+	write_i16(writer, song_length.min(i16::MAX as u32) as i16)?;
+	write_i16(writer, nbs_layers.len() as i16)?;
+	write_string(writer, &project.name)?;
+	write_string(writer, &project.author)?;
+	write_string(writer, "")?; // original author
+	write_string(writer, &project.description)?;
+	write_i16(writer, (project.tempo.tps * 100.0).round() as i16)?;
+	write_u8(writer, 0)?; // auto-saving
+	write_u8(writer, 10)?; // auto-saving duration
+	write_u8(writer, project.time_signature.beats_per_bar.clamp(2, 8))?; // time signature, Note Block Studio only goes from 2 to 8
+	for _ in 0..5 {
+		write_i32(writer, 0)?; // minutes spent, left clicks, right clicks, blocks added, blocks removed
+	}
+	write_string(writer, "")?; // imported file name
+	write_u8(writer, 0)?; // loop on/off
+	write_u8(writer, 0)?; // max loop count
+	write_i16(writer, 0)?; // loop start tick
+
+	// notes
+	let mut last_tick: i64 = -1;
+	let mut last_layer: i64 = -1;
+	for note in &nbs_notes {
+		if note.tick as i64 != last_tick {
+			if last_tick >= 0 {
+				write_i16(writer, 0)?; // end of the previous tick
+			}
+			// jumps are read back as unsigned
+			let jump = note.tick as i64 - last_tick;
+			if jump > u16::MAX as i64 {
+				return Err(too_big(format!("There are {jump} empty ticks before tick {}, Note Block Studio files can only skip {}", note.tick, u16::MAX)));
+			}
+			write_i16(writer, jump as u16 as i16)?;
+			last_tick = note.tick as i64;
+			last_layer = -1;
+		}
+		// always fits, there are at most i16::MAX layers
+		write_i16(writer, (note.layer as i64 - last_layer) as i16)?;
+		last_layer = note.layer as i64;
+		write_u8(writer, note.instrument)?;
+		write_u8(writer, note.key)?;
+		write_u8(writer, note.velocity)?;
+		write_u8(writer, note.panning)?;
+		write_i16(writer, note.pitch)?;
+	}
+	if last_tick >= 0 {
+		write_i16(writer, 0)?;
+	}
+	write_i16(writer, 0)?;
+
+	// layers
+	for (name, layer) in &nbs_layers {
+		write_string(writer, name)?;
+		write_u8(writer, layer.locked as u8)?;
+		write_u8(writer, layer.volume.min(100))?;
+		write_u8(writer, (layer.pan.clamp(-100, 100) as i16 + 100) as u8)?; // stereo, 100 is center
+	}
+
+	// custom instruments, embedded sounds can't go in an nbs file so they get a file name to put the sound under
+	write_u8(writer, project.custom_instruments.len().min(u8::MAX as usize) as u8)?;
+	for custom in project.custom_instruments.iter().take(u8::MAX as usize) {
+		write_string(writer, &custom.name)?;
+		if custom.data.is_some() || custom.path.is_empty() {
+			write_string(writer, &format!("{}.ogg", custom.name))?;
+		} else {
+			write_string(writer, &custom.path)?;
+		}
+		write_u8(writer, custom.base_note.saturating_sub(KEY_OFFSET).min(MAX_KEY))?;
+		write_u8(writer, 0)?; // press piano key
+	}
+	Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn read_back(project: &Project) -> Project {
+		let mut data: Vec<u8> = Vec::new();
+		write(&mut data, project).unwrap();
+		read(&mut data.as_slice()).unwrap()
+	}
+
+	#[test]
+	fn round_trip() {
+		let mut project = Project::new();
+		project.name = "Song".to_string();
+		project.tempo = TempoTrack::new(12.5);
+		project.layers[0] = Layer::new("Bass".to_string(), 1);
+		project.layers[0].volume = 80;
+		project.layers[0].pan = -30;
+		project.layers[0].locked = true;
+		let mut note = Note::new(3, 60);
+		note.volume = 45;
+		note.pan = 25;
+		note.cents = -40;
+		project.add_note(0, note);
+		project.add_note(0, Note::new(7, 54));
+
+		let read = read_back(&project);
+		assert_eq!(read.name, "Song");
+		assert_eq!(read.tempo.tps, 12.5);
+		assert_eq!(read.layers.len(), 1);
+		let layer = &read.layers[0];
+		assert_eq!((layer.name.as_str(), layer.instrument, layer.volume, layer.pan, layer.locked), ("Bass", 1, 80, -30, true));
+		let notes: Vec<(u32, u8, u8, i8, i16)> = layer.notes.iter().map(|note| (note.time, note.note, note.volume, note.pan, note.cents)).collect();
+		assert_eq!(notes, vec![(3, 60, 45, 25, -40), (7, 54, 100, 0, 0)]);
+	}
+
+	#[test]
+	fn chords_go_on_extra_layers() {
+		let mut project = Project::new();
+		project.layers[0] = Layer::new("Piano".to_string(), 0);
+		for key in [60, 64, 67] {
+			project.add_note(0, Note::new(0, key));
+		}
+		project.add_note(0, Note::new(4, 62));
+
+		let read = read_back(&project);
+		let names: Vec<&str> = read.layers.iter().map(|layer| layer.name.as_str()).collect();
+		assert_eq!(names, vec!["Piano", "Piano (2)", "Piano (3)"]);
+		let keys: Vec<Vec<(u32, u8)>> = read.layers.iter().map(|layer| layer.notes.iter().map(|note| (note.time, note.note)).collect()).collect();
+		assert_eq!(keys, vec![vec![(0, 60), (4, 62)], vec![(0, 64)], vec![(0, 67)]]);
+	}
+
+	#[test]
+	fn too_long_a_gap() {
+		let mut project = Project::new();
+		project.add_note(0, Note::new(0, 60));
+		project.add_note(0, Note::new(70_000, 60));
+		let error = write(&mut Vec::new(), &project).err().unwrap();
+		assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+	}
+
+	#[test]
+	fn custom_instruments() {
+		let mut project = Project::new();
+		project.custom_instruments.push(CustomInstrument::new("Drum".to_string(), "drum.ogg".to_string()));
+		project.layers[0] = Layer::new("Custom".to_string(), FIRST_CUSTOM_INSTRUMENT);
+		project.add_note(0, Note::new(2, 66));
+
+		let read = read_back(&project);
+		assert_eq!(read.custom_instruments.len(), 1);
+		assert_eq!(read.custom_instruments[0].name, "Drum");
+		assert_eq!(read.custom_instruments[0].base_note, 66);
+		assert_eq!(read.layers[0].instrument, FIRST_CUSTOM_INSTRUMENT);
+	}
+
+	#[test]
+	fn too_many_custom_instruments() {
+		let mut project = Project::new();
+		for index in 0..250 {
+			project.custom_instruments.push(CustomInstrument::new(format!("Sound {index}"), format!("{index}.ogg")));
+		}
+		project.layers[0] = Layer::new("Custom".to_string(), FIRST_CUSTOM_INSTRUMENT + 5);
+		project.add_note(0, Note::new(0, 66));
+
+		let read = read_back(&project);
+		assert_eq!(read.custom_instruments.len(), MAX_CUSTOM_INSTRUMENTS);
+		assert_eq!(read.layers[0].instrument, FIRST_CUSTOM_INSTRUMENT + 5);
+		assert!(read.validate().is_ok());
+	}
+
+	// the header up to the notes, the same for every version apart from the start and the loop settings
+	fn old_header(data: &mut Vec<u8>, tempo: i16) {
+		write_string(data, "Old song").unwrap();
+		write_string(data, "Someone").unwrap();
+		write_string(data, "").unwrap(); // original author
+		write_string(data, "").unwrap(); // description
+		write_i16(data, tempo).unwrap();
+		write_u8(data, 0).unwrap(); // auto-saving
+		write_u8(data, 10).unwrap(); // auto-saving duration
+		write_u8(data, 3).unwrap(); // time signature
+		for _ in 0..5 {
+			write_i32(data, 0).unwrap();
+		}
+		write_string(data, "").unwrap(); // imported file name
+	}
+
+	// two notes on layer 0, at tick 0 and 4, without the volume, panning and pitch from version 4
+	fn old_notes(data: &mut Vec<u8>, instrument: u8, keys: [u8; 2]) {
+		for (jump, key) in [1, 4].into_iter().zip(keys) {
+			write_i16(data, jump).unwrap(); // to the next tick
+			write_i16(data, 1).unwrap(); // to layer 0
+			write_u8(data, instrument).unwrap();
+			write_u8(data, key).unwrap();
+			write_i16(data, 0).unwrap(); // end of the tick
+		}
+		write_i16(data, 0).unwrap(); // end of the notes
+	}
+
+	#[test]
+	fn classic_file() {
+		let mut data: Vec<u8> = Vec::new();
+		write_i16(&mut data, 5).unwrap(); // song length, never 0 in classic files
+		write_i16(&mut data, 1).unwrap(); // layer count
+		old_header(&mut data, 1000);
+		old_notes(&mut data, 1, [39, 45]);
+		write_string(&mut data, "Melody").unwrap();
+		write_u8(&mut data, 50).unwrap(); // volume
+
+		let project = read(&mut data.as_slice()).unwrap();
+		assert_eq!(project.name, "Old song");
+		assert_eq!(project.author, "Someone");
+		assert_eq!(project.tempo.tps, 10.0);
+		assert_eq!(project.layers.len(), 1);
+		assert_eq!(project.layers[0].name, "Melody");
+		assert_eq!(project.layers[0].instrument, 1);
+		assert_eq!(project.layers[0].volume, 50);
+		let notes: Vec<(u32, u8)> = project.layers[0].notes.iter().map(|note| (note.time, note.note)).collect();
+		assert_eq!(notes, vec![(0, 60), (4, 66)]);
+	}
+
+	#[test]
+	fn version_3_file() {
+		let mut data: Vec<u8> = Vec::new();
+		write_i16(&mut data, 0).unwrap();
+		write_u8(&mut data, 3).unwrap(); // version
+		write_u8(&mut data, 16).unwrap(); // vanilla instrument count
+		write_i16(&mut data, 5).unwrap(); // song length
+		write_i16(&mut data, 1).unwrap(); // layer count
+		old_header(&mut data, 1250);
+		old_notes(&mut data, 16, [45, 33]);
+		write_string(&mut data, "Drums").unwrap();
+		write_u8(&mut data, 70).unwrap(); // volume
+		write_u8(&mut data, 150).unwrap(); // stereo
+		write_u8(&mut data, 1).unwrap(); // custom instrument count
+		write_string(&mut data, "Boom").unwrap();
+		write_string(&mut data, "boom.ogg").unwrap();
+		write_u8(&mut data, 45).unwrap(); // key
+		write_u8(&mut data, 0).unwrap(); // press piano key
+
+		let project = read(&mut data.as_slice()).unwrap();
+		assert_eq!(project.tempo.tps, 12.5);
+		assert_eq!(project.layers.len(), 1);
+		assert_eq!(project.layers[0].name, "Drums");
+		assert_eq!(project.layers[0].volume, 70);
+		assert_eq!(project.layers[0].pan, 50);
+		assert_eq!(project.layers[0].instrument, FIRST_CUSTOM_INSTRUMENT);
+		assert_eq!(project.custom_instruments.len(), 1);
+		assert_eq!(project.custom_instruments[0].name, "Boom");
+		assert_eq!(project.custom_instruments[0].base_note, 66);
+		let notes: Vec<(u32, u8)> = project.layers[0].notes.iter().map(|note| (note.time, note.note)).collect();
+		assert_eq!(notes, vec![(0, 66), (4, 54)]);
+	}
+}
@@ -1,14 +1,45 @@
-use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, io::{self, Read}, ops::Range, sync::Arc};
+
+use serde::{Deserialize, Serialize, de::IgnoredAny};
+
+// Refers to a note for as long as it exists, even when the notes around it change. Copies get a new one.
+// 0 means the note hasn't been given one yet, Project::add_note and Project::assign_note_ids give them out
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct NoteId(u64);
 
 #[derive(Serialize, Deserialize, Clone, Copy)]
 pub struct Note {
+	#[serde(default)]
+	pub id: NoteId,
 	pub time: u32,
 	pub note: u8,
+	// 0 - 100 %, like nbs velocity
+	#[serde(default = "default_volume")]
+	pub volume: u8,
+	// -100 is left, 100 is right, added to the layer's pan
+	#[serde(default)]
+	pub pan: i8,
+	// fine pitch offset, 100 cents is a semitone
+	#[serde(default)]
+	pub cents: i16,
+}
+
+fn default_volume() -> u8 {
+	100
 }
 
 impl Note {
 	pub fn new(time: u32, note: u8) -> Self {
-		Self { time, note }
+		Self { id: NoteId::default(), time, note, volume: 100, pan: 0, cents: 0 }
+	}
+
+	pub fn gain(&self) -> f32 {
+		self.volume.min(100) as f32 / 100.0
+	}
+
+	// in semitones, with the fine pitch
+	pub fn pitch(&self) -> f32 {
+		self.note as f32 + self.cents as f32 / 100.0
 	}
 }
 
@@ -16,7 +47,35 @@ impl Note {
 pub struct Layer {
 	pub name: String,
 	pub instrument: u8,
+	// sorted by time so playback and drawing only look at the notes they need,
+	// use insert_note to add one and sort_notes after changing times
 	pub notes: Vec<Note>,
+	// default pan for every note on the layer
+	#[serde(default)]
+	pub pan: i8,
+	// 0 - 100 %, on top of the notes' volume
+	#[serde(default = "default_volume")]
+	pub volume: u8,
+	#[serde(default)]
+	pub mute: bool,
+	#[serde(default)]
+	pub solo: bool,
+	// locked layers can't be edited
+	#[serde(default)]
+	pub locked: bool,
+	#[serde(default = "default_color")]
+	pub color: [u8; 3],
+}
+
+const LAYER_COLORS: [[u8; 3]; 8] = [[90, 160, 255], [255, 110, 90], [110, 220, 110], [255, 200, 70], [200, 120, 255], [80, 220, 220], [255, 130, 200], [200, 200, 200]];
+
+fn default_color() -> [u8; 3] {
+	LAYER_COLORS[0]
+}
+
+// Colors for new layers, going around the palette
+pub fn layer_color(index: usize) -> [u8; 3] {
+	LAYER_COLORS[index % LAYER_COLORS.len()]
 }
 
 impl Layer {
@@ -24,18 +83,426 @@ impl Layer {
 		Self {
 			name,
 			instrument,
-			notes: Vec::new()
+			notes: Vec::new(),
+			pan: 0,
+			volume: 100,
+			mute: false,
+			solo: false,
+			locked: false,
+			color: default_color(),
+		}
+	}
+
+	// notes with start <= time < end
+	pub fn notes_between(&self, times: Range<u32>) -> &[Note] {
+		let start = self.notes.partition_point(|note| note.time < times.start);
+		let end = self.notes.partition_point(|note| note.time < times.end).max(start);
+		&self.notes[start..end]
+	}
+
+	pub fn notes_at(&self, time: u32) -> &[Note] {
+		self.notes_between(time..time.saturating_add(1))
+	}
+
+	// index of the note with this time and key
+	pub fn find_note(&self, time: u32, key: u8) -> Option<usize> {
+		let start = self.notes.partition_point(|note| note.time < time);
+		self.notes_at(time).iter().position(|note| note.note == key).map(|index| start + index)
+	}
+
+	// after the notes with the same time, so the order stays the same as pushing them
+	pub fn insert_note(&mut self, note: Note) {
+		let index = self.notes.partition_point(|other| other.time <= note.time);
+		self.notes.insert(index, note);
+	}
+
+	// stable, notes with the same time keep their order
+	pub fn sort_notes(&mut self) {
+		self.notes.sort_by_key(|note| note.time);
+	}
+
+	// note volume with the layer volume
+	pub fn note_gain(&self, note: &Note) -> f32 {
+		note.gain() * self.volume.min(100) as f32 / 100.0
+	}
+
+	// -1.0 to 1.0
+	pub fn note_pan(&self, note: &Note) -> f32 {
+		(note.pan as i32 + self.pan as i32).clamp(-100, 100) as f32 / 100.0
+	}
+}
+
+// A sound from outside of vanilla, like Note Block Studio's custom instruments
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CustomInstrument {
+	pub name: String,
+	// ogg or wav file, relative paths start at the project file
+	#[serde(default)]
+	pub path: String,
+	// the sound file itself, used instead of the path so the project works on other computers,
+	// shared so undo snapshots don't each get a copy
+	#[serde(default, with = "embedded_sound")]
+	pub data: Option<Arc<[u8]>>,
+	// the note that plays the sound at its normal speed
+	pub base_note: u8,
+	// sound event for datapack export, for sounds from a resource pack
+	#[serde(default)]
+	pub sound_event: String,
+}
+
+// Embedded sounds are saved as base64, older project files have them as an array of numbers
+mod embedded_sound {
+	use std::sync::Arc;
+
+	use base64::{Engine, engine::general_purpose::STANDARD};
+	use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+	pub fn serialize<S: Serializer>(data: &Option<Arc<[u8]>>, serializer: S) -> Result<S::Ok, S::Error> {
+		match data {
+			Some(data) => serializer.serialize_some(&STANDARD.encode(data)),
+			None => serializer.serialize_none(),
 		}
 	}
+
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Data {
+		Base64(String),
+		Bytes(Vec<u8>),
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Arc<[u8]>>, D::Error> {
+		match Option::<Data>::deserialize(deserializer)? {
+			Some(Data::Base64(text)) => STANDARD.decode(text).map(|data| Some(data.into())).map_err(D::Error::custom),
+			Some(Data::Bytes(data)) => Ok(Some(data.into())),
+			None => Ok(None),
+		}
+	}
+}
+
+impl CustomInstrument {
+	pub fn new(name: String, path: String) -> Self {
+		Self { name, path, data: None, base_note: 66, sound_event: String::new() }
+	}
+}
+
+// Layer::instrument values from this one on are custom instruments
+pub const FIRST_CUSTOM_INSTRUMENT: u8 = 16;
+// so every instrument still fits in a u8
+pub const MAX_CUSTOM_INSTRUMENTS: usize = (u8::MAX - FIRST_CUSTOM_INSTRUMENT) as usize;
+
+// slower than this and the song barely moves, 0 or less would never get anywhere
+pub const MIN_TPS: f32 = 0.1;
+
+// The song plays at tps from tick on, until the next change
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct TempoChange {
+	pub tick: u32,
+	pub tps: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TempoTrack {
+	// ticks per second at the start
+	pub tps: f32,
+	// sorted by tick, all after tick 0
+	#[serde(default)]
+	pub changes: Vec<TempoChange>,
+}
+
+impl Default for TempoTrack {
+	fn default() -> Self {
+		Self::new(10.0)
+	}
+}
+
+impl TempoTrack {
+	pub fn new(tps: f32) -> Self {
+		Self { tps: tps.max(MIN_TPS), changes: Vec::new() }
+	}
+
+	pub fn tps_at(&self, tick: f64) -> f32 {
+		match self.changes.partition_point(|change| change.tick as f64 <= tick) {
+			0 => self.tps,
+			index => self.changes[index - 1].tps,
+		}
+	}
+
+	// time from the start of the song to tick
+	pub fn seconds_at(&self, tick: f64) -> f64 {
+		let mut seconds = 0.0;
+		let (mut last_tick, mut tps) = (0.0, self.tps);
+		for change in &self.changes {
+			if change.tick as f64 >= tick {
+				break;
+			}
+			seconds += (change.tick as f64 - last_tick) / tps as f64;
+			(last_tick, tps) = (change.tick as f64, change.tps);
+		}
+		seconds + (tick - last_tick) / tps as f64
+	}
+
+	// replaces the change on that tick if there already is one, tick 0 changes the starting tempo
+	pub fn set(&mut self, tick: u32, tps: f32) {
+		let tps = tps.max(MIN_TPS);
+		if tick == 0 {
+			self.tps = tps;
+			return;
+		}
+		match self.changes.binary_search_by_key(&tick, |change| change.tick) {
+			Ok(index) => self.changes[index].tps = tps,
+			Err(index) => self.changes.insert(index, TempoChange { tick, tps }),
+		}
+	}
+
+	// after editing the ticks of changes directly
+	pub fn sort(&mut self) {
+		self.changes.sort_by_key(|change| change.tick);
+		// a change moved to the start becomes the starting tempo
+		while let Some(first) = self.changes.first() && first.tick == 0 {
+			self.tps = first.tps;
+			self.changes.remove(0);
+		}
+		self.changes.dedup_by_key(|change| change.tick);
+	}
+
+	// every tempo the song uses
+	pub fn all(&self) -> impl Iterator<Item = f32> {
+		std::iter::once(self.tps).chain(self.changes.iter().map(|change| change.tps))
+	}
+}
+
+// For the ruler and the grid, a beat is ticks_per_beat ticks long
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct TimeSignature {
+	pub beats_per_bar: u8,
+	pub ticks_per_beat: u8,
+}
+
+impl Default for TimeSignature {
+	fn default() -> Self {
+		Self { beats_per_bar: 4, ticks_per_beat: 4 }
+	}
+}
+
+impl TimeSignature {
+	pub fn ticks_per_beat(&self) -> u32 {
+		self.ticks_per_beat.max(1) as u32
+	}
+
+	pub fn ticks_per_bar(&self) -> u32 {
+		self.beats_per_bar.max(1) as u32 * self.ticks_per_beat()
+	}
+
+	// (bar, beat) counting from 1, the first tick is 1:1
+	pub fn bar_beat(&self, tick: u32) -> (u32, u32) {
+		(tick / self.ticks_per_bar() + 1, tick % self.ticks_per_bar() / self.ticks_per_beat() + 1)
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Marker {
+	pub tick: u32,
+	pub name: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Project {
 	pub layers: Vec<Layer>,
+	#[serde(default)]
+	pub name: String,
+	#[serde(default)]
+	pub author: String,
+	#[serde(default)]
+	pub description: String,
+	#[serde(default)]
+	pub custom_instruments: Vec<CustomInstrument>,
+	#[serde(default)]
+	next_note_id: u64,
+	// old projects didn't save the tempo, they get 10 TPS
+	#[serde(default)]
+	pub tempo: TempoTrack,
+	#[serde(default)]
+	pub time_signature: TimeSignature,
+	// sorted by tick
+	#[serde(default)]
+	pub markers: Vec<Marker>,
+}
+
+// Just enough of a project file to see which layers have a color
+#[derive(Deserialize)]
+struct LayerColors {
+	layers: Vec<LayerColor>,
+}
+
+#[derive(Deserialize)]
+struct LayerColor {
+	color: Option<IgnoredAny>,
 }
 
 impl Project {
 	pub fn new() -> Self {
-		Self{ layers: vec![Layer::new("Layer 1".to_string(), 0)] }
+		Self{
+			layers: vec![Layer::new("Layer 1".to_string(), 0)],
+			name: String::new(), author: String::new(), description: String::new(),
+			custom_instruments: Vec::new(),
+			next_note_id: 1,
+			tempo: TempoTrack::default(),
+			time_signature: TimeSignature::default(),
+			markers: Vec::new(),
+		}
+	}
+
+	pub fn new_note_id(&mut self) -> NoteId {
+		let id = NoteId(self.next_note_id.max(1));
+		self.next_note_id = id.0 + 1;
+		id
 	}
-}
\ No newline at end of file
+
+	pub fn add_note(&mut self, layer: usize, mut note: Note) -> NoteId {
+		note.id = self.new_note_id();
+		self.layers[layer].insert_note(note);
+		note.id
+	}
+
+	// For notes that were made without add_note, like from imports and old project files
+	pub fn assign_note_ids(&mut self) {
+		let highest = self.layers.iter().flat_map(|layer| &layer.notes).map(|note| note.id.0).max().unwrap_or(0);
+		self.next_note_id = self.next_note_id.max(highest + 1);
+		let mut seen: HashSet<NoteId> = HashSet::new();
+		for layer in 0..self.layers.len() {
+			for index in 0..self.layers[layer].notes.len() {
+				let id = self.layers[layer].notes[index].id;
+				if id == NoteId::default() || !seen.insert(id) {
+					let id = self.new_note_id();
+					seen.insert(id);
+					self.layers[layer].notes[index].id = id;
+				}
+			}
+		}
+	}
+
+	pub fn add_marker(&mut self, tick: u32, name: String) {
+		let index = self.markers.partition_point(|marker| marker.tick <= tick);
+		self.markers.insert(index, Marker { tick, name });
+	}
+
+	// tick is the end of the last note
+	pub fn length(&self) -> u32 {
+		self.layers.iter().filter_map(|layer| layer.notes.last()).map(|note| note.time + 1).max().unwrap_or(0)
+	}
+
+	pub fn sort_notes(&mut self) {
+		for layer in &mut self.layers {
+			layer.sort_notes();
+		}
+	}
+
+	pub fn custom_instrument(&self, instrument: u8) -> Option<&CustomInstrument> {
+		self.custom_instruments.get(instrument.checked_sub(FIRST_CUSTOM_INSTRUMENT)? as usize)
+	}
+
+	// layers that aren't muted, if any layer is solo only the solo ones
+	pub fn audible_layers(&self) -> impl Iterator<Item = &Layer> {
+		let solo = self.layers.iter().any(|layer| layer.solo);
+		self.layers.iter().filter(move |layer| !layer.mute && (layer.solo || !solo))
+	}
+
+	// Reads a .nbm project file
+	pub fn read(mut reader: impl Read) -> io::Result<Project> {
+		let mut data: Vec<u8> = Vec::new();
+		reader.read_to_end(&mut data)?;
+		// straight from the text so errors say where in the file they are
+		let mut project: Project = serde_json::from_slice(&data)?;
+		// files from before layers had colors get them going around the palette, like new layers
+		let colors: LayerColors = serde_json::from_slice(&data)?;
+		for (index, (layer, color)) in project.layers.iter_mut().zip(colors.layers).enumerate() {
+			if color.color.is_none() {
+				layer.color = layer_color(index);
+			}
+		}
+		project.validate()?;
+		Ok(project)
+	}
+
+	// For project files, the editor expects these to always hold
+	pub fn validate(&self) -> io::Result<()> {
+		let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidData, message));
+		if self.layers.is_empty() {
+			return invalid("The project has no layers".to_string());
+		}
+		if self.custom_instruments.len() > MAX_CUSTOM_INSTRUMENTS {
+			return invalid(format!("The project has {} custom instruments, the most it can have is {MAX_CUSTOM_INSTRUMENTS}", self.custom_instruments.len()));
+		}
+		let instruments = FIRST_CUSTOM_INSTRUMENT as usize + self.custom_instruments.len();
+		if let Some(layer) = self.layers.iter().find(|layer| layer.instrument as usize >= instruments) {
+			return invalid(format!("Layer \"{}\" uses instrument {}, which doesn't exist", layer.name, layer.instrument));
+		}
+		if let Some(tps) = self.tempo.all().find(|&tps| tps < MIN_TPS) {
+			return invalid(format!("The project has a tempo of {tps} ticks per second"));
+		}
+		Ok(())
+	}
+
+	pub fn base_note(&self, instrument: u8) -> u8 {
+		self.custom_instrument(instrument).map(|custom| custom.base_note).unwrap_or(66)
+	}
+
+	// whether the custom instruments would sound any different, names and sound events don't matter
+	pub fn same_custom_sounds(&self, other: &Project) -> bool {
+		self.custom_instruments.len() == other.custom_instruments.len()
+			&& self.custom_instruments.iter().zip(&other.custom_instruments)
+				.all(|(a, b)| a.path == b.path && a.data == b.data && a.base_note == b.base_note)
+	}
+
+	// layers that used it go back to harp
+	pub fn remove_custom_instrument(&mut self, index: usize) {
+		self.custom_instruments.remove(index);
+		let removed = FIRST_CUSTOM_INSTRUMENT + index as u8;
+		for layer in &mut self.layers {
+			if layer.instrument == removed {
+				layer.instrument = 0;
+			} else if layer.instrument > removed {
+				layer.instrument -= 1;
+			}
+		}
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn old_files_get_layer_colors() {
+		let json = r#"{"layers": [{"name": "A", "instrument": 0, "notes": []}, {"name": "B", "instrument": 0, "notes": [], "color": [1, 2, 3]}, {"name": "C", "instrument": 0, "notes": []}],
+			"name": "", "author": "", "description": "", "custom_instruments": [], "tempo": {"tps": 10.0}}"#;
+		let project = Project::read(json.as_bytes()).unwrap();
+		let colors: Vec<[u8; 3]> = project.layers.iter().map(|layer| layer.color).collect();
+		assert_eq!(colors, vec![layer_color(0), [1, 2, 3], layer_color(2)]);
+	}
+
+	#[test]
+	fn embedded_sounds_are_base64() {
+		let mut project = Project::new();
+		let mut custom = CustomInstrument::new("Drum".to_string(), String::new());
+		custom.data = Some(vec![0, 1, 2, 255].into());
+		project.custom_instruments.push(custom);
+
+		let json = serde_json::to_string(&project).unwrap();
+		assert!(json.contains("\"data\":\"AAEC/w==\""), "{json}");
+		let read = Project::read(json.as_bytes()).unwrap();
+		assert_eq!(read.custom_instruments[0].data.as_deref(), Some(&[0, 1, 2, 255][..]));
+
+		// how older versions saved it
+		let old = json.replace("\"AAEC/w==\"", "[0, 1, 2, 255]");
+		let read = Project::read(old.as_bytes()).unwrap();
+		assert_eq!(read.custom_instruments[0].data.as_deref(), Some(&[0, 1, 2, 255][..]));
+	}
+
+	#[test]
+	fn errors_have_a_location() {
+		let json = "{\"layers\": [{\"name\": \"A\", \"instrument\": \"harp\", \"notes\": []}]}";
+		let error = Project::read(json.as_bytes()).err().unwrap();
+		assert!(error.to_string().contains("line 1 column"), "{error}");
+	}
+}
@@ -0,0 +1,130 @@
+// Offline mixing of a whole project, used for audio export
+use std::io::{self, Write};
+
+use rodio::{Source, buffer::SamplesBuffer};
+
+use crate::project::Project;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BitDepth {
+	Int16,
+	Int24,
+	Float32,
+}
+
+impl BitDepth {
+	pub const ALL: [BitDepth; 3] = [BitDepth::Int16, BitDepth::Int24, BitDepth::Float32];
+
+	pub fn name(self) -> &'static str {
+		match self {
+			BitDepth::Int16 => "16 bit",
+			BitDepth::Int24 => "24 bit",
+			BitDepth::Float32 => "32 bit float",
+		}
+	}
+
+	fn bits(self) -> u16 {
+		match self {
+			BitDepth::Int16 => 16,
+			BitDepth::Int24 => 24,
+			BitDepth::Float32 => 32,
+		}
+	}
+}
+
+pub struct WavOptions {
+	pub sample_rate: u32,
+	pub bit_depth: BitDepth,
+	pub normalize: bool,
+}
+
+impl WavOptions {
+	pub fn new() -> Self {
+		Self { sample_rate: 44100, bit_depth: BitDepth::Int16, normalize: true }
+	}
+}
+
+// Playback speed for a pitch in semitones, base_note plays the sound as it is
+pub fn note_speed(pitch: f32, base_note: u8) -> f32 {
+	2.0_f32.powf((pitch - base_note as f32) / 12.0)
+}
+
+// Left and right volume for a pan from -1.0 to 1.0, the middle keeps both sides at full volume
+pub fn pan_gains(pan: f32) -> (f32, f32) {
+	((1.0 - pan).min(1.0), (1.0 + pan).min(1.0))
+}
+
+// Mixes every note of every audible layer into one stereo buffer, left and right samples interleaved
+pub fn render(project: &Project, sounds: &[SamplesBuffer], sample_rate: u32) -> Vec<f32> {
+	let sounds: Vec<(Vec<f32>, u32)> = sounds.iter().map(|sound| (sound.clone().collect(), sound.sample_rate())).collect();
+	let mut output: Vec<f32> = Vec::new();
+
+	for layer in project.audible_layers() {
+		let Some((samples, source_rate)) = sounds.get(layer.instrument as usize) else { continue };
+		let base_note = project.base_note(layer.instrument);
+		for note in &layer.notes {
+			let start = (project.tempo.seconds_at(note.time as f64) * sample_rate as f64) as usize;
+			// how far to move in the source for every output sample
+			let step = note_speed(note.pitch(), base_note) as f64 * *source_rate as f64 / sample_rate as f64;
+			let length = (samples.len().saturating_sub(1) as f64 / step) as usize;
+			let (left, right) = pan_gains(layer.note_pan(note));
+			let gain = layer.note_gain(note);
+			let (left, right) = (left * gain, right * gain);
+			if output.len() < (start + length) * 2 {
+				output.resize((start + length) * 2, 0.0);
+			}
+			for i in 0..length {
+				let position = i as f64 * step;
+				let index = position as usize;
+				let fraction = (position - index as f64) as f32;
+				let sample = samples[index] * (1.0 - fraction) + samples[index + 1] * fraction;
+				output[(start + i) * 2] += sample * left;
+				output[(start + i) * 2 + 1] += sample * right;
+			}
+		}
+	}
+
+	output
+}
+
+pub fn write_wav(writer: &mut impl Write, samples: &[f32], options: &WavOptions) -> io::Result<()> {
+	let gain = if options.normalize {
+		let peak = samples.iter().fold(0.0_f32, |peak, sample| peak.max(sample.abs()));
+		if peak > 0.0 {0.99 / peak} else {1.0}
+	} else {
+		1.0
+	};
+
+	let channels: u16 = 2;
+	let bytes_per_sample = options.bit_depth.bits() / 8;
+	let data_size = samples.len() as u32 * bytes_per_sample as u32;
+	let padding = data_size % 2; // chunks have to be an even size
+	let format: u16 = if options.bit_depth == BitDepth::Float32 {3} else {1}; // ieee float or pcm
+
+	writer.write_all(b"RIFF")?;
+	writer.write_all(&(36 + data_size + padding).to_le_bytes())?;
+	writer.write_all(b"WAVE")?;
+	writer.write_all(b"fmt ")?;
+	writer.write_all(&16_u32.to_le_bytes())?;
+	writer.write_all(&format.to_le_bytes())?;
+	writer.write_all(&channels.to_le_bytes())?;
+	writer.write_all(&options.sample_rate.to_le_bytes())?;
+	writer.write_all(&(options.sample_rate * bytes_per_sample as u32 * channels as u32).to_le_bytes())?; // byte rate
+	writer.write_all(&(bytes_per_sample * channels).to_le_bytes())?; // block align
+	writer.write_all(&options.bit_depth.bits().to_le_bytes())?;
+	writer.write_all(b"data")?;
+	writer.write_all(&data_size.to_le_bytes())?;
+
+	for sample in samples {
+		let sample = (sample * gain).clamp(-1.0, 1.0);
+		match options.bit_depth {
+			BitDepth::Int16 => writer.write_all(&((sample * i16::MAX as f32) as i16).to_le_bytes())?,
+			BitDepth::Int24 => writer.write_all(&((sample * 8388607.0) as i32).to_le_bytes()[..3])?,
+			BitDepth::Float32 => writer.write_all(&sample.to_le_bytes())?,
+		}
+	}
+	if padding != 0 {
+		writer.write_all(&[0])?;
+	}
+	Ok(())
+}
@@ -0,0 +1,304 @@
+// Sponge schematic (.schem, version 2) export of a playable redstone build
+//
+// The song runs along +x from a button at x = 0. Every tick with notes gets a powered "hub" block on the
+// main repeater line, and a redstone dust branch goes out from the hub along z (alternating sides so
+// neighbouring branches never touch). The blocks under the dust are weakly powered, which plays the note
+// blocks on both sides of the branch, all at the same time since dust has no delay.
+use std::{collections::HashMap, io::{self, Write}};
+
+use flate2::{Compression, write::GzEncoder};
+
+use crate::{app::MINECRAFT_INSTRUMENT_NAMES, project::Project};
+
+// minecraft 1.20.1, every block state used here has been around since 1.13
+const DATA_VERSION: i32 = 3465;
+// dust signal strength runs out after 15 blocks
+const MAX_BRANCH_LENGTH: usize = 15;
+const MAX_CHORD: usize = MAX_BRANCH_LENGTH * 2;
+
+// y levels
+const SUPPORT_Y: i32 = 0; // only under sand so it doesn't fall
+const INSTRUMENT_Y: i32 = 1;
+const NOTE_Y: i32 = 2; // note blocks and the floor of the redstone
+const REDSTONE_Y: i32 = 3;
+const BUTTON_Y: i32 = 4;
+
+// The block that has to go under a note block for every instrument, in SOUND_FILE_NAMES order
+const INSTRUMENT_BLOCKS: [&str; 16] = [
+	"minecraft:dirt", // harp is anything else
+	"minecraft:oak_planks",
+	"minecraft:stone",
+	"minecraft:sand",
+	"minecraft:glass",
+	"minecraft:white_wool",
+	"minecraft:clay",
+	"minecraft:gold_block",
+	"minecraft:packed_ice",
+	"minecraft:bone_block[axis=y]",
+	"minecraft:iron_block",
+	"minecraft:soul_sand",
+	"minecraft:pumpkin",
+	"minecraft:emerald_block",
+	"minecraft:hay_block[axis=y]",
+	"minecraft:glowstone",
+];
+
+pub struct SchematicReport {
+	// (tps, redstone ticks per project tick) for tempos that couldn't be made out of whole redstone ticks,
+	// those parts of the build play at a slightly different speed
+	pub inexact_tempos: Vec<(f32, u32)>,
+	pub out_of_range: usize,
+	// note blocks have no fine pitch, these play the nearest note instead
+	pub cents: usize,
+	// custom instruments have no note block sound
+	pub custom: usize,
+	// more notes on one tick than a branch can power
+	pub dropped: usize,
+}
+
+// Redstone ticks (1/10 s) per project tick, rounded
+pub fn repeater_delay(tps: f32) -> u32 {
+	(10.0 / tps).round().max(1.0) as u32
+}
+
+// Minimal big endian NBT writer
+mod nbt {
+	pub const END: u8 = 0;
+	pub const SHORT: u8 = 2;
+	pub const INT: u8 = 3;
+	pub const BYTE_ARRAY: u8 = 7;
+	pub const LIST: u8 = 9;
+	pub const COMPOUND: u8 = 10;
+	pub const INT_ARRAY: u8 = 11;
+
+	pub fn name(buf: &mut Vec<u8>, value: &str) {
+		buf.extend((value.len() as u16).to_be_bytes());
+		buf.extend(value.as_bytes());
+	}
+
+	pub fn tag(buf: &mut Vec<u8>, kind: u8, tag_name: &str) {
+		buf.push(kind);
+		name(buf, tag_name);
+	}
+
+	pub fn short(buf: &mut Vec<u8>, tag_name: &str, value: i16) {
+		tag(buf, SHORT, tag_name);
+		buf.extend(value.to_be_bytes());
+	}
+
+	pub fn int(buf: &mut Vec<u8>, tag_name: &str, value: i32) {
+		tag(buf, INT, tag_name);
+		buf.extend(value.to_be_bytes());
+	}
+}
+
+struct Blocks {
+	blocks: HashMap<(i32, i32, i32), String>,
+}
+
+impl Blocks {
+	fn set(&mut self, x: i32, y: i32, z: i32, block: impl Into<String>) {
+		self.blocks.insert((x, y, z), block.into());
+	}
+}
+
+fn repeater(delay: u32) -> String {
+	// facing is the input side, so west means the signal goes east
+	format!("minecraft:repeater[delay={delay},facing=west,locked=false,powered=false]")
+}
+
+pub fn write(writer: &mut impl Write, project: &Project) -> io::Result<SchematicReport> {
+	let mut report = SchematicReport {
+		inexact_tempos: Vec::new(),
+		out_of_range: 0,
+		cents: 0,
+		custom: 0,
+		dropped: 0,
+	};
+	for tps in project.tempo.all() {
+		let delay = repeater_delay(tps);
+		if (10.0 / tps - delay as f32).abs() > 0.01 && !report.inexact_tempos.contains(&(tps, delay)) {
+			report.inexact_tempos.push((tps, delay));
+		}
+	}
+
+	// (tick, instrument, note block note), sorted so the output is always the same
+	let mut notes: Vec<(u32, u8, u8)> = Vec::new();
+	for layer in &project.layers {
+		if project.custom_instrument(layer.instrument).is_some() {
+			report.custom += layer.notes.len();
+			continue;
+		}
+		for note in &layer.notes {
+			let key = note.pitch().round();
+			if (54.0..=78.0).contains(&key) {
+				notes.push((note.time, layer.instrument, key as u8 - 54));
+				if note.cents != 0 {
+					report.cents += 1;
+				}
+			} else {
+				report.out_of_range += 1;
+			}
+		}
+	}
+	notes.sort();
+	notes.dedup();
+
+	let mut blocks = Blocks { blocks: HashMap::new() };
+	// start button on top of the first block
+	blocks.set(0, NOTE_Y, 0, "minecraft:stone");
+	blocks.set(0, REDSTONE_Y, 0, "minecraft:stone");
+	blocks.set(0, BUTTON_Y, 0, "minecraft:stone_button[face=floor,facing=east,powered=false]");
+
+	let mut x = 0;
+	let mut last_tick: i64 = 0;
+	let mut hub_index = 0;
+	let mut max_branch = 0;
+	let mut start = 0;
+	while start < notes.len() {
+		let tick = notes[start].0;
+		let end = start + notes[start..].iter().take_while(|note| note.0 == tick).count();
+		let chord = &notes[start..end];
+		start = end;
+
+		// repeaters up to this tick, the first hub needs at least one
+		let mut remaining = (last_tick..tick as i64).map(|tick| repeater_delay(project.tempo.tps_at(tick as f64))).sum::<u32>().max(1);
+		last_tick = tick as i64;
+		while remaining > 0 {
+			x += 1;
+			let step = remaining.min(4);
+			blocks.set(x, NOTE_Y, 0, "minecraft:stone");
+			blocks.set(x, REDSTONE_Y, 0, repeater(step));
+			remaining -= step;
+		}
+
+		// hub
+		x += 1;
+		blocks.set(x, NOTE_Y, 0, "minecraft:stone");
+		blocks.set(x, REDSTONE_Y, 0, "minecraft:stone");
+
+		// branch
+		let side = if hub_index % 2 == 0 {1} else {-1};
+		hub_index += 1;
+		if chord.len() > MAX_CHORD {
+			report.dropped += chord.len() - MAX_CHORD;
+		}
+		let chord = &chord[..chord.len().min(MAX_CHORD)];
+		let length = chord.len().div_ceil(2);
+		max_branch = max_branch.max(length);
+		for cell in 0..length {
+			let z = side * (cell as i32 + 1);
+			blocks.set(x, NOTE_Y, z, "minecraft:stone");
+			blocks.set(x, REDSTONE_Y, z, "minecraft:redstone_wire[east=none,north=side,power=0,south=side,west=none]");
+		}
+		for (index, &(_, instrument, note)) in chord.iter().enumerate() {
+			let z = side * (index as i32 / 2 + 1);
+			let note_x = if index % 2 == 0 {x - 1} else {x + 1};
+			let instrument_block = INSTRUMENT_BLOCKS[instrument as usize % INSTRUMENT_BLOCKS.len()];
+			let instrument_state = MINECRAFT_INSTRUMENT_NAMES[instrument as usize % MINECRAFT_INSTRUMENT_NAMES.len()];
+			blocks.set(note_x, NOTE_Y, z, format!("minecraft:note_block[instrument={instrument_state},note={note},powered=false]"));
+			blocks.set(note_x, INSTRUMENT_Y, z, instrument_block);
+			if instrument_block == "minecraft:sand" {
+				blocks.set(note_x, SUPPORT_Y, z, "minecraft:stone");
+			}
+		}
+	}
+
+	// block data, the index is x + z * width + y * width * length
+	let width = x + 2;
+	if width > i16::MAX as i32 {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("The build would be {width} blocks long, schematics can only be {} long", i16::MAX)));
+	}
+	let height = BUTTON_Y + 1;
+	let length = max_branch as i32 * 2 + 1;
+	let z_offset = max_branch as i32;
+	let mut palette: Vec<String> = vec!["minecraft:air".to_string()];
+	let mut block_data: Vec<u8> = Vec::new();
+	for y in 0..height {
+		for z in 0..length {
+			for x in 0..width {
+				let block = blocks.blocks.get(&(x, y, z - z_offset)).map(|block| block.as_str()).unwrap_or("minecraft:air");
+				let mut id = match palette.iter().position(|entry| entry == block) {
+					Some(id) => id,
+					None => {
+						palette.push(block.to_string());
+						palette.len() - 1
+					}
+				};
+				// varint
+				loop {
+					if id < 0x80 {
+						block_data.push(id as u8);
+						break;
+					}
+					block_data.push((id & 0x7F) as u8 | 0x80);
+					id >>= 7;
+				}
+			}
+		}
+	}
+
+	let mut buf: Vec<u8> = Vec::new();
+	nbt::tag(&mut buf, nbt::COMPOUND, "Schematic");
+	nbt::int(&mut buf, "Version", 2);
+	nbt::int(&mut buf, "DataVersion", DATA_VERSION);
+	nbt::short(&mut buf, "Width", width as i16);
+	nbt::short(&mut buf, "Height", height as i16);
+	nbt::short(&mut buf, "Length", length as i16);
+	nbt::tag(&mut buf, nbt::INT_ARRAY, "Offset");
+	buf.extend(3_i32.to_be_bytes());
+	buf.extend([0_u8; 12]);
+	nbt::int(&mut buf, "PaletteMax", palette.len() as i32);
+	nbt::tag(&mut buf, nbt::COMPOUND, "Palette");
+	for (id, block) in palette.iter().enumerate() {
+		nbt::int(&mut buf, block, id as i32);
+	}
+	buf.push(nbt::END);
+	nbt::tag(&mut buf, nbt::BYTE_ARRAY, "BlockData");
+	buf.extend((block_data.len() as i32).to_be_bytes());
+	buf.extend(&block_data);
+	nbt::tag(&mut buf, nbt::LIST, "BlockEntities");
+	buf.push(nbt::COMPOUND);
+	buf.extend(0_i32.to_be_bytes());
+	buf.push(nbt::END);
+
+	// gzip with no timestamp so the same song always gives the same file
+	let mut encoder = GzEncoder::new(writer, Compression::default());
+	encoder.write_all(&buf)?;
+	encoder.finish()?;
+	Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::project::{Layer, Note};
+
+	fn song() -> Project {
+		let mut project = Project::new();
+		project.layers[0] = Layer::new("Harp".to_string(), 0);
+		project.layers.push(Layer::new("Sand".to_string(), 3));
+		for (time, key) in [(0, 54), (0, 60), (3, 66), (10, 78)] {
+			project.add_note(0, Note::new(time, key));
+		}
+		project.add_note(1, Note::new(3, 70));
+		project
+	}
+
+	#[test]
+	fn same_song_same_bytes() {
+		let (mut first, mut second): (Vec<u8>, Vec<u8>) = (Vec::new(), Vec::new());
+		write(&mut first, &song()).unwrap();
+		write(&mut second, &song()).unwrap();
+		assert!(!first.is_empty());
+		assert_eq!(first, second);
+	}
+
+	#[test]
+	fn too_long() {
+		let mut project = Project::new();
+		project.add_note(0, Note::new(0, 60));
+		project.add_note(0, Note::new(200_000, 60));
+		assert!(write(&mut Vec::new(), &project).is_err());
+	}
+}
@@ -0,0 +1,271 @@
+// Song playback that runs in the audio thread, notes start on the exact sample they belong to
+// and it keeps going when the window isn't being drawn
+use std::{sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}, mpsc::{self, Receiver, Sender}}, time::Duration};
+
+use rodio::{OutputStream, Source, buffer::SamplesBuffer};
+
+use crate::{project::{Project, TempoTrack}, render};
+
+// mono samples and their sample rate
+type Sound = (Vec<f32>, u32);
+
+struct SongNote {
+	time: u32,
+	sound: usize,
+	speed: f32,
+	left: f32,
+	right: f32,
+}
+
+// Everything the audio thread needs to play a project, sorted by time
+pub struct Song {
+	notes: Vec<SongNote>,
+	tempo: TempoTrack,
+}
+
+impl Song {
+	pub fn new(project: &Project) -> Self {
+		let mut notes: Vec<SongNote> = Vec::new();
+		for layer in project.audible_layers() {
+			let base_note = project.base_note(layer.instrument);
+			for note in &layer.notes {
+				let (left, right) = render::pan_gains(layer.note_pan(note));
+				let gain = layer.note_gain(note);
+				notes.push(SongNote {
+					time: note.time,
+					sound: layer.instrument as usize,
+					speed: render::note_speed(note.pitch(), base_note),
+					left: left * gain,
+					right: right * gain,
+				});
+			}
+		}
+		notes.sort_by_key(|note| note.time);
+		Self { notes, tempo: project.tempo.clone() }
+	}
+}
+
+enum Command {
+	Song(Song),
+	Sounds(Vec<Sound>),
+	Loop(Option<(u32, u32)>),
+	Play,
+	Pause,
+	Seek(f64),
+}
+
+// A note that's still ringing
+struct Voice {
+	sound: usize,
+	position: f64,
+	step: f64,
+	left: f32,
+	right: f32,
+}
+
+// The rodio source, lives in the mixer for as long as the Player does
+struct Sequencer {
+	commands: Receiver<Command>,
+	position: Arc<AtomicU64>,
+	playing: Arc<AtomicBool>,
+	sample_rate: u32,
+	song: Song,
+	sounds: Vec<Sound>,
+	voices: Vec<Voice>,
+	// start and end tick, wraps back to the start when it gets to the end
+	loop_region: Option<(u32, u32)>,
+	// in ticks
+	time: f64,
+	next_note: usize,
+	// the right sample of the frame that was just mixed
+	right: Option<f32>,
+}
+
+impl Sequencer {
+	// first note that hasn't been played yet
+	fn find_next_note(&mut self) {
+		self.next_note = self.song.notes.partition_point(|note| (note.time as f64) < self.time);
+	}
+
+	fn run_commands(&mut self) {
+		while let Ok(command) = self.commands.try_recv() {
+			match command {
+				Command::Song(song) => {
+					self.song = song;
+					self.find_next_note();
+				}
+				Command::Sounds(sounds) => {
+					self.sounds = sounds;
+					self.voices.clear();
+				}
+				Command::Loop(region) => self.loop_region = region,
+				Command::Play => {
+					self.playing.store(true, Ordering::Relaxed);
+					self.find_next_note();
+				}
+				Command::Pause => self.playing.store(false, Ordering::Relaxed),
+				Command::Seek(time) => {
+					self.time = time;
+					self.position.store(time.to_bits(), Ordering::Relaxed);
+					self.find_next_note();
+				}
+			}
+		}
+	}
+
+	// one stereo frame
+	fn mix(&mut self) -> (f32, f32) {
+		self.run_commands();
+
+		if self.playing.load(Ordering::Relaxed) {
+			// this frame covers time..time + step, start every note in there
+			let step = self.song.tempo.tps_at(self.time).max(0.0) as f64 / self.sample_rate as f64;
+			// only loop when playing inside of the loop, starting after it plays on like normal
+			let loop_region = self.loop_region.filter(|&(_, end)| self.time < end as f64);
+			while let Some(note) = self.song.notes.get(self.next_note) && (note.time as f64) < self.time + step
+					&& loop_region.is_none_or(|(_, end)| note.time < end) {
+				if let Some((_, source_rate)) = self.sounds.get(note.sound) {
+					self.voices.push(Voice {
+						sound: note.sound,
+						position: 0.0,
+						step: note.speed as f64 * *source_rate as f64 / self.sample_rate as f64,
+						left: note.left,
+						right: note.right,
+					});
+				}
+				self.next_note += 1;
+			}
+			self.time += step;
+			if let Some((start, end)) = loop_region && self.time >= end as f64 {
+				self.time = start as f64 + (self.time - end as f64);
+				// the notes right on the start still have to play, even though time is a bit past it now
+				self.next_note = self.song.notes.partition_point(|note| note.time < start);
+			}
+			self.position.store(self.time.to_bits(), Ordering::Relaxed);
+		}
+
+		// already playing notes ring out when paused, same as a note block
+		let (mut left, mut right) = (0.0, 0.0);
+		let sounds = &self.sounds;
+		self.voices.retain_mut(|voice| {
+			let Some((samples, _)) = sounds.get(voice.sound) else { return false };
+			let index = voice.position as usize;
+			if index + 1 >= samples.len() {
+				return false;
+			}
+			let fraction = (voice.position - index as f64) as f32;
+			let sample = samples[index] * (1.0 - fraction) + samples[index + 1] * fraction;
+			left += sample * voice.left;
+			right += sample * voice.right;
+			voice.position += voice.step;
+			true
+		});
+		(left, right)
+	}
+}
+
+impl Iterator for Sequencer {
+	type Item = f32;
+
+	fn next(&mut self) -> Option<f32> {
+		if let Some(right) = self.right.take() {
+			return Some(right);
+		}
+		let (left, right) = self.mix();
+		self.right = Some(right);
+		Some(left)
+	}
+}
+
+impl Source for Sequencer {
+	fn current_span_len(&self) -> Option<usize> {
+		None
+	}
+
+	fn channels(&self) -> u16 {
+		2
+	}
+
+	fn sample_rate(&self) -> u32 {
+		self.sample_rate
+	}
+
+	fn total_duration(&self) -> Option<Duration> {
+		None
+	}
+}
+
+// The UI side of the sequencer
+pub struct Player {
+	commands: Sender<Command>,
+	position: Arc<AtomicU64>,
+	playing: Arc<AtomicBool>,
+	loop_region: Option<(u32, u32)>,
+}
+
+impl Player {
+	pub fn new(stream: &OutputStream) -> Self {
+		let (commands, receiver) = mpsc::channel();
+		let position = Arc::new(AtomicU64::new(0.0_f64.to_bits()));
+		let playing = Arc::new(AtomicBool::new(false));
+		stream.mixer().add(Sequencer {
+			commands: receiver,
+			position: position.clone(),
+			playing: playing.clone(),
+			sample_rate: stream.config().sample_rate(),
+			song: Song { notes: Vec::new(), tempo: TempoTrack::default() },
+			sounds: Vec::new(),
+			voices: Vec::new(),
+			loop_region: None,
+			time: 0.0,
+			next_note: 0,
+			right: None,
+		});
+		Self { commands, position, playing, loop_region: None }
+	}
+
+	// the audio thread is gone if sending fails, nothing to do about that here
+	fn send(&self, command: Command) {
+		let _ = self.commands.send(command);
+	}
+
+	pub fn set_song(&self, song: Song) {
+		self.send(Command::Song(song));
+	}
+
+	pub fn set_sounds(&self, sounds: &[SamplesBuffer]) {
+		self.send(Command::Sounds(sounds.iter().map(|sound| (sound.clone().collect(), sound.sample_rate())).collect()));
+	}
+
+	pub fn set_loop(&mut self, region: Option<(u32, u32)>) {
+		if region != self.loop_region {
+			self.loop_region = region;
+			self.send(Command::Loop(region));
+		}
+	}
+
+	pub fn play(&self) {
+		// so the UI sees it straight away instead of after the next audio callback
+		self.playing.store(true, Ordering::Relaxed);
+		self.send(Command::Play);
+	}
+
+	pub fn pause(&self) {
+		self.playing.store(false, Ordering::Relaxed);
+		self.send(Command::Pause);
+	}
+
+	pub fn seek(&self, time: f64) {
+		self.position.store(time.to_bits(), Ordering::Relaxed);
+		self.send(Command::Seek(time));
+	}
+
+	pub fn is_playing(&self) -> bool {
+		self.playing.load(Ordering::Relaxed)
+	}
+
+	// in ticks
+	pub fn position(&self) -> f64 {
+		f64::from_bits(self.position.load(Ordering::Relaxed))
+	}
+}
@@ -0,0 +1,143 @@
+// Instrument samples, the defaults are built into the binary so it runs from anywhere
+use std::{env, fs::{self, File}, io::{Cursor, Read, Seek}, path::{Path, PathBuf}};
+
+use lewton::inside_ogg::OggStreamReader;
+use rodio::buffer::SamplesBuffer;
+
+use crate::{app::SOUND_FILE_NAMES, project::CustomInstrument};
+
+// in SOUND_FILE_NAMES order
+const EMBEDDED_SOUNDS: [&[u8]; 16] = [
+	include_bytes!("../sounds/harp.ogg"),
+	include_bytes!("../sounds/dbass.ogg"),
+	include_bytes!("../sounds/bdrum.ogg"),
+	include_bytes!("../sounds/sdrum.ogg"),
+	include_bytes!("../sounds/click.ogg"),
+	include_bytes!("../sounds/guitar.ogg"),
+	include_bytes!("../sounds/flute.ogg"),
+	include_bytes!("../sounds/bell.ogg"),
+	include_bytes!("../sounds/icechime.ogg"),
+	include_bytes!("../sounds/xylobone.ogg"),
+	include_bytes!("../sounds/iron_xylophone.ogg"),
+	include_bytes!("../sounds/cow_bell.ogg"),
+	include_bytes!("../sounds/didgeridoo.ogg"),
+	include_bytes!("../sounds/bit.ogg"),
+	include_bytes!("../sounds/banjo.ogg"),
+	include_bytes!("../sounds/pling.ogg"),
+];
+
+// set this to a folder to use other sounds instead, otherwise a sounds folder next to the executable is used
+const SOUNDS_DIR_VARIABLE: &str = "NOTEBLOCK_MUSIC_SOUNDS";
+
+fn override_dir() -> Option<PathBuf> {
+	if let Some(dir) = env::var_os(SOUNDS_DIR_VARIABLE) {
+		return Some(PathBuf::from(dir));
+	}
+	let dir = env::current_exe().ok()?.parent()?.join("sounds");
+	dir.is_dir().then_some(dir)
+}
+
+pub fn load_ogg(reader: impl Read + Seek) -> Result<SamplesBuffer, String> {
+	let mut reader = OggStreamReader::new(reader).map_err(|error| error.to_string())?;
+	let channels: u16 = 1;
+	let sample_rate = reader.ident_hdr.audio_sample_rate;
+
+	let mut sampels: Vec<f32> = Vec::new();
+	while let Some(packet) = reader.read_dec_packet_generic::<Vec<Vec<f32>>>().map_err(|error| error.to_string())? {
+		if let Some(first_channel) = packet.first() {
+			sampels.extend(first_channel);
+		}
+	}
+
+	Ok(SamplesBuffer::new(channels, sample_rate, sampels))
+}
+
+// Loads all 16 instruments, a file in the override folder replaces the built in sound with the same name
+pub fn load_sounds() -> Result<Vec<SamplesBuffer>, String> {
+	let dir = override_dir();
+	let mut sounds: Vec<SamplesBuffer> = Vec::new();
+	for (name, embedded) in SOUND_FILE_NAMES.iter().zip(EMBEDDED_SOUNDS) {
+		let path = dir.as_ref().map(|dir| dir.join(format!("{name}.ogg"))).filter(|path| path.is_file());
+		let sound = match &path {
+			Some(path) => File::open(path).map_err(|error| error.to_string()).and_then(load_ogg),
+			None => load_ogg(Cursor::new(embedded)),
+		};
+		match sound {
+			Ok(sound) => sounds.push(sound),
+			Err(error) => {
+				let source = path.map(|path| path.display().to_string()).unwrap_or_else(|| format!("built in {name}.ogg"));
+				return Err(format!("Failed to load the {name} sound from {source}: {error}"));
+			}
+		}
+	}
+	Ok(sounds)
+}
+
+// Only the first channel is kept, same as load_ogg
+pub fn load_wav(data: &[u8]) -> Result<SamplesBuffer, String> {
+	if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+		return Err("Not a WAV file".to_string());
+	}
+	let mut format: Option<(u16, u16, u32, u16)> = None; // (format, channels, sample rate, bits)
+	let mut pos = 12;
+	while pos + 8 <= data.len() {
+		let id = &data[pos..pos + 4];
+		let size = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]) as usize;
+		let chunk = &data[pos + 8..(pos + 8 + size).min(data.len())];
+		if id == b"fmt " && chunk.len() >= 16 {
+			let mut kind = u16::from_le_bytes([chunk[0], chunk[1]]);
+			if kind == 0xFFFE && chunk.len() >= 26 {
+				// extensible, the real format is at the start of the sub format guid
+				kind = u16::from_le_bytes([chunk[24], chunk[25]]);
+			}
+			let channels = u16::from_le_bytes([chunk[2], chunk[3]]);
+			let sample_rate = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+			let bits = u16::from_le_bytes([chunk[14], chunk[15]]);
+			format = Some((kind, channels, sample_rate, bits));
+		} else if id == b"data" {
+			let (kind, channels, sample_rate, bits) = format.ok_or("WAV data before its format")?;
+			if sample_rate == 0 {
+				return Err("Invalid WAV sample rate".to_string());
+			}
+			let frame_size = (bits as usize / 8) * channels.max(1) as usize;
+			if frame_size == 0 {
+				return Err("Invalid WAV format".to_string());
+			}
+			let mut sampels: Vec<f32> = Vec::new();
+			for frame in chunk.chunks_exact(frame_size) {
+				sampels.push(match (kind, bits) {
+					(1, 8) => (frame[0] as f32 - 128.0) / 128.0,
+					(1, 16) => i16::from_le_bytes([frame[0], frame[1]]) as f32 / 32768.0,
+					(1, 24) => i32::from_le_bytes([0, frame[0], frame[1], frame[2]]) as f32 / 2147483648.0,
+					(1, 32) => i32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]) as f32 / 2147483648.0,
+					(3, 32) => f32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]),
+					_ => return Err(format!("Unsupported WAV format ({bits} bit, format {kind})")),
+				});
+			}
+			return Ok(SamplesBuffer::new(1, sample_rate, sampels));
+		}
+		pos += 8 + size + size % 2;
+	}
+	Err("WAV file has no audio".to_string())
+}
+
+// Ogg or WAV, whichever the bytes are
+pub fn load_sound(data: &[u8]) -> Result<SamplesBuffer, String> {
+	if data.starts_with(b"OggS") {
+		load_ogg(Cursor::new(data))
+	} else {
+		load_wav(data)
+	}
+}
+
+pub fn load_custom(custom: &CustomInstrument, project_dir: Option<&Path>) -> Result<SamplesBuffer, String> {
+	if let Some(data) = &custom.data {
+		return load_sound(data);
+	}
+	let path = match project_dir {
+		Some(dir) => dir.join(&custom.path),
+		None => PathBuf::from(&custom.path),
+	};
+	let data = fs::read(&path).map_err(|error| format!("{}: {error}", path.display()))?;
+	load_sound(&data).map_err(|error| format!("{}: {error}", path.display()))
+}